@@ -0,0 +1,43 @@
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn size(&self) -> Vec3 {
+        self.max.sub(self.min)
+    }
+
+    /// Slab test returning the parametric entry and exit distances along `ray`,
+    /// clipped to start at zero.
+    pub fn intersect(&self, ray: Ray) -> Option<(f32, f32)> {
+        let mut t_min = 0.0_f32;
+        let mut t_max = f32::MAX;
+
+        let axes = [
+            (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+            (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+            (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+        ];
+
+        for (origin, direction, min, max) in axes.iter() {
+            let inv = 1.0 / direction;
+            let mut t0 = (min - origin) * inv;
+            let mut t1 = (max - origin) * inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max <= t_min {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+}
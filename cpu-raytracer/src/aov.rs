@@ -0,0 +1,170 @@
+use crate::exr::{self, Channel};
+use crate::film::{encode_rgb8, Aovs, Film};
+use crate::math::clamp01;
+use crate::sampler::hash;
+use crate::vec3::Vec3;
+
+use std::fs::File;
+use std::io;
+
+/// Output variable that can be written next to the beauty image.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Aov {
+    Depth,
+    Normal,
+    Albedo,
+    Emission,
+    Direct,
+    Indirect,
+    MaterialId,
+    ObjectId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Emission,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::MaterialId,
+        Aov::ObjectId,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().copied().find(|a| a.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Emission => "emission",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectId => "object_id",
+        }
+    }
+
+    /// EXR channel suffixes and the value of each channel for a pixel.
+    fn channels(&self, a: &Aovs) -> Vec<(&'static str, f32)> {
+        let id = |id: Option<usize>| id.map_or(-1.0, |i| i as f32);
+        match self {
+            Aov::Depth => vec![("Z", a.depth)],
+            Aov::Normal => vec![("X", a.normal.x), ("Y", a.normal.y), ("Z", a.normal.z)],
+            Aov::Albedo | Aov::Emission | Aov::Direct | Aov::Indirect => {
+                let c = self.color(a);
+                vec![("R", c.x), ("G", c.y), ("B", c.z)]
+            }
+            Aov::MaterialId => vec![("id", id(a.material_id.map(usize::from)))],
+            Aov::ObjectId => vec![("id", id(a.object_id))],
+        }
+    }
+
+    fn color(&self, a: &Aovs) -> Vec3 {
+        match self {
+            Aov::Albedo => a.albedo,
+            Aov::Emission => a.emission,
+            Aov::Direct => a.direct,
+            Aov::Indirect => a.indirect,
+            _ => Vec3::zero(),
+        }
+    }
+
+    /// Visualizes the variable as 8-bit RGB, top row first. Depth is
+    /// normalized to the farthest hit, normals are mapped from [-1, 1] and
+    /// ids get a random color each.
+    pub fn visualize(&self, film: &Film) -> Vec<u8> {
+        let aovs: Vec<Aovs> = (0..film.height)
+            .flat_map(|y| (0..film.width).map(move |x| (x, y)))
+            .map(|(x, y)| film.aovs(x, y))
+            .collect();
+
+        let max_depth = aovs.iter().fold(0.0f32, |m, a| m.max(a.depth));
+        let id_color = |id: Option<usize>| match id {
+            Some(id) => {
+                let h = hash(&[id as u32]);
+                let channel = |shift: u32| ((h >> shift) & 0xff) as f32 / 255.0;
+                Vec3::new(channel(0), channel(8), channel(16))
+            }
+            None => Vec3::zero(),
+        };
+
+        let linear = |c: Vec3| Vec3::new(c.x * c.x, c.y * c.y, c.z * c.z);
+        let colors: Vec<Vec3> = aovs
+            .iter()
+            .map(|a| match self {
+                Aov::Depth if a.depth > 0.0 => {
+                    let d = clamp01(1.0 - a.depth / max_depth);
+                    linear(Vec3::new(d, d, d))
+                }
+                Aov::Depth => Vec3::zero(),
+                Aov::Normal => linear(a.normal.scale(0.5).add(Vec3::new(0.5, 0.5, 0.5))),
+                Aov::MaterialId => linear(id_color(a.material_id.map(usize::from))),
+                Aov::ObjectId => linear(id_color(a.object_id)),
+                _ => self.color(a),
+            })
+            .collect();
+
+        encode_rgb8(film.width, film.height, &colors)
+    }
+}
+
+/// Writes the beauty image as the unnamed `R`, `G` and `B` channels, and
+/// each light path buffer of the film and each of `aovs` as a layer of a
+/// single multi-layer EXR.
+pub fn write_exr(path: &str, film: &Film, aovs: &[Aov]) -> io::Result<()> {
+    let (width, height) = (film.width, film.height);
+    let top_first = |y: usize| height - y - 1;
+
+    let mut channels = rgb_channels("", &film.resolve(), width, height);
+    for (i, name) in film.light_path_names().iter().enumerate() {
+        let prefix = format!("{}.", name);
+        channels.extend(rgb_channels(
+            &prefix,
+            &film.resolve_light_path(i),
+            width,
+            height,
+        ));
+    }
+
+    for aov in aovs {
+        let pixels: Vec<Vec<(&str, f32)>> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, top_first(y))))
+            .map(|(x, y)| aov.channels(&film.aovs(x, y)))
+            .collect();
+        for (i, (suffix, _)) in pixels[0].iter().enumerate() {
+            channels.push(Channel {
+                name: format!("{}.{}", aov.name(), suffix),
+                values: pixels.iter().map(|p| p[i].1).collect(),
+            });
+        }
+    }
+
+    let file = File::create(path)?;
+    exr::write(&mut io::BufWriter::new(file), width, height, &channels)
+}
+
+/// Splits colors stored bottom row first into top row first `R`, `G` and
+/// `B` channels named with `prefix`.
+fn rgb_channels(prefix: &str, colors: &[Vec3], width: usize, height: usize) -> Vec<Channel> {
+    let mut rgb = [Vec::new(), Vec::new(), Vec::new()];
+    for y in (0..height).rev() {
+        for c in &colors[y * width..][..width] {
+            rgb[0].push(c.x);
+            rgb[1].push(c.y);
+            rgb[2].push(c.z);
+        }
+    }
+    ["R", "G", "B"]
+        .iter()
+        .zip(rgb)
+        .map(|(suffix, values)| Channel {
+            name: format!("{}{}", prefix, suffix),
+            values,
+        })
+        .collect()
+}
@@ -0,0 +1,620 @@
+//! Bidirectional path tracing after Veach, "Robust Monte Carlo Methods for
+//! Light Transport Simulation" (1997), with the vertex bookkeeping of pbrt.
+//!
+//! For every camera sample a camera subpath and a light subpath are traced
+//! and every pair of their prefixes is connected, with the contributions
+//! combined by the balance heuristic. Light subpaths start on emissive
+//! spheres. Connections can only be made at diffuse standard surfaces; all
+//! other materials are treated like specular ones and are only crossed by
+//! extending a subpath. Volumes only absorb: subpath segments and
+//! connections are attenuated by a ratio tracking estimate of their
+//! transmittance, but nothing scatters inside them.
+
+use crate::camera::Camera;
+use crate::film::SplatBuffer;
+use crate::integrator::{
+    contribution, record_first_hit, sky_color, Integrator, SampleOutput, BOUNCES, DIRECT, EMITTED,
+    INDIRECT,
+};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::{cosine_hemisphere, cosine_hemisphere_pdf, uniform_sphere};
+use crate::scene::{pick_light, Light, Scene};
+use crate::stats;
+use crate::vec3::Vec3;
+
+use std::f32::consts::{FRAC_1_PI, PI};
+
+/// Longest subpaths, in vertices, so that no connected path has more
+/// bounces than the path tracer would follow.
+const MAX_VERTICES: usize = BOUNCES + 1;
+
+#[derive(Copy, Clone, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    kind: VertexKind,
+    point: Vec3,
+    /// Geometric normal facing the side the subpath arrived from. The view
+    /// direction for the camera and the outward normal for a light.
+    normal: Vec3,
+    beta: Vec3,
+    /// Area densities of sampling this vertex from the previous vertex of
+    /// its own subpath, and in the reverse direction from the next.
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    /// The vertex was crossed by sampling its material and cannot be
+    /// connected to.
+    delta: bool,
+    /// Albedo of the lambertian BSDF at connectible surfaces.
+    lambertian: Option<Vec3>,
+    emission: Vec3,
+    material: u8,
+    object: usize,
+}
+
+impl Vertex {
+    fn endpoint(kind: VertexKind, point: Vec3, normal: Vec3, beta: Vec3, pdf_fwd: f32) -> Vertex {
+        Vertex {
+            kind,
+            point,
+            normal,
+            beta,
+            pdf_fwd,
+            pdf_rev: 0.0,
+            delta: false,
+            lambertian: None,
+            emission: Vec3::zero(),
+            material: 0,
+            object: 0,
+        }
+    }
+
+    fn connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Camera | VertexKind::Light => true,
+            VertexKind::Surface => self.lambertian.is_some(),
+        }
+    }
+
+    /// BSDF value for light arriving from `next` and leaving towards `prev`;
+    /// for a light vertex the radiance it emits towards `next`.
+    fn f(&self, prev: Option<&Vertex>, next: &Vertex) -> Vec3 {
+        let wi = next.point.sub(self.point).normalize();
+        match self.kind {
+            VertexKind::Light if self.normal.dot(wi) > 0.0 => self.emission,
+            VertexKind::Surface => match (self.lambertian, prev) {
+                (Some(albedo), Some(prev)) => {
+                    let wo = prev.point.sub(self.point);
+                    if self.normal.dot(wi) > 0.0 && self.normal.dot(wo) > 0.0 {
+                        albedo.scale(FRAC_1_PI)
+                    } else {
+                        Vec3::zero()
+                    }
+                }
+                _ => Vec3::zero(),
+            },
+            _ => Vec3::zero(),
+        }
+    }
+}
+
+/// Converts a solid angle density at `from` to an area density at `to`.
+fn convert_density(pdf: f32, from: &Vertex, to: &Vertex) -> f32 {
+    let w = to.point.sub(from.point);
+    let distance_squared = w.square_magnitude();
+    if distance_squared == 0.0 {
+        return 0.0;
+    }
+    let pdf = pdf / distance_squared;
+    if to.kind == VertexKind::Camera {
+        pdf
+    } else {
+        pdf * to.normal.dot(w.normalize()).abs()
+    }
+}
+
+/// Geometry term between two vertices, without visibility.
+fn geometry(a: &Vertex, b: &Vertex) -> f32 {
+    let w = b.point.sub(a.point);
+    let distance_squared = w.square_magnitude();
+    let w = w.normalize();
+    let cos = |v: &Vertex| {
+        if v.kind == VertexKind::Camera {
+            1.0
+        } else {
+            v.normal.dot(w).abs()
+        }
+    };
+    cos(a) * cos(b) / distance_squared
+}
+
+/// Fraction of light reaching `b` from `a`: zero if a surface is in the
+/// way, else the transmittance of the volumes in between.
+fn transmittance(scene: &Scene, a: Vec3, b: Vec3, sampler: &mut dyn Sampler) -> f32 {
+    let ray = Ray {
+        origin: a,
+        direction: b.sub(a),
+    };
+    stats::count(|s| s.shadow_rays += 1);
+    if scene.intersect(ray).is_some_and(|hit| hit.t < 1.0 - 1e-3) {
+        return 0.0;
+    }
+    scene.transmittance(ray, 1.0, sampler)
+}
+
+fn remap0(pdf: f32) -> f32 {
+    if pdf != 0.0 {
+        pdf
+    } else {
+        1.0
+    }
+}
+
+pub struct Bidirectional {
+    camera: Camera,
+    width: usize,
+    height: usize,
+    lights: Vec<Light>,
+}
+
+impl Bidirectional {
+    pub fn new(camera: Camera, width: usize, height: usize) -> Self {
+        Bidirectional {
+            camera,
+            width,
+            height,
+            lights: Vec::new(),
+        }
+    }
+
+    fn light_pdf(&self, object: usize) -> f32 {
+        self.lights
+            .iter()
+            .find(|l| l.sphere == object)
+            .map_or(0.0, |l| l.pdf)
+    }
+
+    /// Area density of a light subpath starting at `v`.
+    fn pdf_light_origin(&self, scene: &Scene, v: &Vertex) -> f32 {
+        match scene.spheres.get(v.object) {
+            Some((sphere, _)) => {
+                self.light_pdf(v.object) / (4.0 * PI * sphere.radius * sphere.radius)
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Area density at `to` of a light subpath leaving the emitter at `v`.
+    fn pdf_light(&self, v: &Vertex, to: &Vertex) -> f32 {
+        let w = to.point.sub(v.point).normalize();
+        convert_density(cosine_hemisphere_pdf(v.normal.dot(w)), v, to)
+    }
+
+    /// Area density at `next` of sampling it from `v`, reached from `prev`.
+    fn pdf(&self, v: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let w = next.point.sub(v.point).normalize();
+        let pdf = match v.kind {
+            VertexKind::Camera => self.camera.importance(w).1,
+            VertexKind::Light => return self.pdf_light(v, next),
+            VertexKind::Surface => match (v.lambertian, prev) {
+                (Some(_), Some(prev)) if v.normal.dot(prev.point.sub(v.point)) > 0.0 => {
+                    cosine_hemisphere_pdf(v.normal.dot(w))
+                }
+                _ => 0.0,
+            },
+        };
+        convert_density(pdf, v, next)
+    }
+
+    /// Extends `path` from its last vertex along `ray`, sampled with solid
+    /// angle density `pdf`. Returns the throughput and ray of a path that
+    /// escaped the scene.
+    fn random_walk(
+        &self,
+        scene: &Scene,
+        mut ray: Ray,
+        mut beta: Vec3,
+        mut pdf: f32,
+        sampler: &mut dyn Sampler,
+        path: &mut Vec<Vertex>,
+    ) -> Option<(Vec3, Ray)> {
+        while path.len() < MAX_VERTICES {
+            let hit = scene.intersect(ray);
+            let t_max = hit.map_or(f32::MAX, |h| h.t);
+            beta = beta.scale(scene.transmittance(ray, t_max, sampler));
+            let hit = match hit {
+                Some(hit) => hit,
+                None => return Some((beta, ray)),
+            };
+            let material = scene.materials[hit.material as usize];
+            let point = ray.at(hit.t);
+            let normal = if hit.normal.dot(ray.direction) > 0.0 {
+                hit.normal.scale(-1.0)
+            } else {
+                hit.normal
+            };
+
+            let prev = path[path.len() - 1];
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface,
+                point,
+                normal,
+                beta,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+                delta: false,
+                lambertian: material.lambertian(),
+                emission: material.emission,
+                material: hit.material,
+                object: hit.object,
+            };
+            vertex.pdf_fwd = convert_density(pdf, &prev, &vertex);
+            path.push(vertex);
+            if path.len() == MAX_VERTICES {
+                break;
+            }
+
+            let pdf_rev = match vertex.lambertian {
+                Some(albedo) => {
+                    let sample = cosine_hemisphere(normal, sampler.get_2d());
+                    if sample.pdf == 0.0 {
+                        break;
+                    }
+                    beta = beta.hadamard(albedo);
+                    pdf = sample.pdf;
+                    ray = Ray {
+                        origin: point,
+                        direction: sample.direction,
+                    };
+                    let wo = ray_back(&prev, &vertex);
+                    cosine_hemisphere_pdf(normal.dot(wo))
+                }
+                None => {
+                    let scatter = match material.scatter(ray, &hit, scene, None, sampler) {
+                        Some(scatter) => scatter,
+                        None => break,
+                    };
+                    beta = beta.hadamard(scatter.attenuation);
+                    pdf = 0.0;
+                    ray = scatter.ray;
+                    let n = path.len();
+                    path[n - 1].delta = true;
+                    0.0
+                }
+            };
+
+            let n = path.len();
+            path[n - 2].pdf_rev = convert_density(pdf_rev, &path[n - 1], &path[n - 2]);
+        }
+        None
+    }
+
+    fn light_subpath(&self, scene: &Scene, sampler: &mut dyn Sampler, path: &mut Vec<Vertex>) {
+        let light = match pick_light(&self.lights, sampler.get_1d()) {
+            Some(light) => light,
+            None => return,
+        };
+
+        let (sphere, material) = &scene.spheres[light.sphere];
+        let normal = uniform_sphere(sampler.get_2d()).direction;
+        let point = sphere.center.add(normal.scale(sphere.radius));
+        let pdf_pos = light.pdf / (4.0 * PI * sphere.radius * sphere.radius);
+
+        let mut vertex = Vertex::endpoint(
+            VertexKind::Light,
+            point,
+            normal,
+            Vec3::one().scale(1.0 / pdf_pos),
+            pdf_pos,
+        );
+        vertex.emission = scene.materials[*material as usize].emission;
+        vertex.object = light.sphere;
+        path.push(vertex);
+
+        let direction = cosine_hemisphere(normal, sampler.get_2d());
+        if direction.pdf == 0.0 {
+            return;
+        }
+        // Le * cos / (pdf_pos * pdf_dir), with the cosine cancelling.
+        let beta = vertex.emission.scale(PI / pdf_pos);
+        let ray = Ray {
+            origin: point,
+            direction: direction.direction,
+        };
+        self.random_walk(scene, ray, beta, direction.pdf, sampler, path);
+    }
+
+    /// Strategy with `s` light and `t >= 2` camera vertices.
+    fn connect(
+        &self,
+        scene: &Scene,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let pt = &camera[t - 1];
+        if s == 0 {
+            // The camera subpath hit an emitter by itself.
+            if pt.emission.square_magnitude() == 0.0 {
+                return Vec3::zero();
+            }
+            let radiance = pt.beta.hadamard(pt.emission);
+            if self.light_pdf(pt.object) == 0.0 {
+                // Not a light subpaths can start on, so no other strategy
+                // can find this path.
+                return radiance;
+            }
+            return radiance.scale(self.mis_weight(scene, light, camera, s, t));
+        }
+
+        let qs = &light[s - 1];
+        if !qs.connectible() || !pt.connectible() || pt.delta || qs.delta {
+            return Vec3::zero();
+        }
+        let prev_q = if s > 1 { Some(&light[s - 2]) } else { None };
+        let radiance = qs
+            .beta
+            .hadamard(qs.f(prev_q, pt))
+            .hadamard(pt.f(Some(&camera[t - 2]), qs))
+            .hadamard(pt.beta)
+            .scale(geometry(qs, pt));
+        if radiance.square_magnitude() == 0.0 {
+            return Vec3::zero();
+        }
+        let transmittance = transmittance(scene, qs.point, pt.point, sampler);
+        if transmittance == 0.0 {
+            return Vec3::zero();
+        }
+        radiance.scale(transmittance * self.mis_weight(scene, light, camera, s, t))
+    }
+
+    /// Strategy with `s` light vertices connected straight to the camera.
+    fn splat(
+        &self,
+        scene: &Scene,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        splats: &SplatBuffer,
+        sampler: &mut dyn Sampler,
+    ) {
+        let qs = &light[s - 1];
+        if !qs.connectible() || qs.delta {
+            return;
+        }
+        let origin = self.camera.origin();
+        let direction = qs.point.sub(origin).normalize();
+        let (u, v) = match self.camera.uv_from_direction(direction) {
+            Some(uv) => uv,
+            None => return,
+        };
+        let importance = self.camera.importance(direction).0;
+
+        let mut sensor = camera[0];
+        sensor.beta = Vec3::one().scale(importance);
+        let prev_q = if s > 1 { Some(&light[s - 2]) } else { None };
+        // The camera is not a surface, so its cosine is not part of the
+        // geometry term and is applied here.
+        let cos = direction.dot(self.camera.view_direction());
+        let radiance = qs
+            .beta
+            .hadamard(qs.f(prev_q, &sensor))
+            .scale(importance * cos * geometry(qs, &sensor));
+        if radiance.square_magnitude() == 0.0 {
+            return;
+        }
+        let transmittance = transmittance(scene, origin, qs.point, sampler);
+        if transmittance == 0.0 {
+            return;
+        }
+
+        let weight = transmittance * self.mis_weight(scene, light, &[sensor], s, 1);
+        splats.add(
+            u * self.width as f32,
+            v * self.height as f32,
+            radiance.scale(weight),
+        );
+    }
+
+    /// Balance heuristic weight of the strategy with `s` light and `t`
+    /// camera vertices, computed from the ratios of the densities of
+    /// generating the same path with the neighbouring strategies.
+    fn mis_weight(
+        &self,
+        scene: &Scene,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let mut l: Vec<Vertex> = light[..s].to_vec();
+        let mut c: Vec<Vertex> = camera[..t].to_vec();
+
+        // Densities at the connection change with the strategy.
+        if s > 0 {
+            l[s - 1].delta = false;
+        }
+        c[t - 1].delta = false;
+        c[t - 1].pdf_rev = if s > 0 {
+            self.pdf(&l[s - 1], s.checked_sub(2).map(|i| &l[i]), &c[t - 1])
+        } else {
+            self.pdf_light_origin(scene, &c[t - 1])
+        };
+        if t > 1 {
+            c[t - 2].pdf_rev = if s > 0 {
+                self.pdf(&c[t - 1], Some(&l[s - 1]), &c[t - 2])
+            } else {
+                self.pdf_light(&c[t - 1], &c[t - 2])
+            };
+        }
+        if s > 0 {
+            l[s - 1].pdf_rev = self.pdf(&c[t - 1], t.checked_sub(2).map(|i| &c[i]), &l[s - 1]);
+        }
+        if s > 1 {
+            l[s - 2].pdf_rev = self.pdf(&l[s - 1], Some(&c[t - 1]), &l[s - 2]);
+        }
+
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap0(c[i].pdf_rev) / remap0(c[i].pdf_fwd);
+            if !c[i].delta && !c[i - 1].delta {
+                sum += ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap0(l[i].pdf_rev) / remap0(l[i].pdf_fwd);
+            let delta_prev = i > 0 && l[i - 1].delta;
+            if !l[i].delta && !delta_prev {
+                sum += ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for Bidirectional {
+    fn preprocess(&mut self, scene: &Scene) {
+        self.lights = scene.lights();
+    }
+
+    /// Radiance arriving along the camera `ray`. Contributions of light
+    /// subpaths connected directly to the camera land on other pixels and
+    /// are added to the output's splats instead.
+    fn li(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        output: &mut SampleOutput,
+    ) -> Vec3 {
+        let mut camera_path = Vec::with_capacity(MAX_VERTICES);
+        let direction = ray.direction.normalize();
+        camera_path.push(Vertex::endpoint(
+            VertexKind::Camera,
+            ray.origin,
+            self.camera.view_direction(),
+            Vec3::one(),
+            1.0,
+        ));
+        let pdf = self.camera.importance(direction).1;
+        let escaped = self.random_walk(scene, ray, Vec3::one(), pdf, sampler, &mut camera_path);
+
+        let mut light_path = Vec::with_capacity(MAX_VERTICES);
+        self.light_subpath(scene, sampler, &mut light_path);
+
+        let mut color = [Vec3::zero(); 3];
+        if let Some((beta, ray)) = escaped {
+            // The sky is not sampled by light subpaths.
+            let c = contribution(camera_path.len() - 1);
+            color[c] = color[c].add(beta.hadamard(sky_color(ray)));
+        }
+
+        if let Some(first) = camera_path.get(1) {
+            let material = scene.materials[first.material as usize];
+            let t = first.point.sub(ray.origin).magnitude() / ray.direction.magnitude();
+            record_first_hit(
+                &mut output.aovs,
+                ray,
+                t,
+                material.albedo(),
+                first.normal,
+                Some(first.material),
+                first.object,
+            );
+        }
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // Emitters seen directly are left to the camera subpath;
+                // both strategies for them get a weight of one.
+                if s + t < 2 || s + t > MAX_VERTICES || (s == 1 && t == 1) {
+                    continue;
+                }
+                if t == 1 {
+                    if s > 0 {
+                        self.splat(scene, &light_path, &camera_path, s, output.splats, sampler);
+                    }
+                    continue;
+                }
+                let c = contribution(s + t - 2);
+                color[c] =
+                    color[c].add(self.connect(scene, &light_path, &camera_path, s, t, sampler));
+            }
+        }
+
+        output.aovs.emission = color[EMITTED];
+        output.aovs.direct = color[DIRECT];
+        output.aovs.indirect = color[INDIRECT];
+        color[EMITTED].add(color[DIRECT]).add(color[INDIRECT])
+    }
+}
+
+/// Unit direction from `vertex` back towards `prev`.
+fn ray_back(prev: &Vertex, vertex: &Vertex) -> Vec3 {
+    prev.point.sub(vertex.point).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator;
+    use crate::render::{self, Settings};
+
+    /// Mean luminance of a small Cornell box render and its standard error,
+    /// from the variance of the pixel means.
+    fn mean_luminance(name: &str, seed: u32) -> (f32, f32) {
+        let settings = Settings {
+            width: 16,
+            height: 12,
+            samples: 64,
+            seed,
+            threads: 1,
+            integrator: integrator::find(name).unwrap(),
+            ..Settings::default()
+        };
+        let camera = Camera::look_at(
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.0, -0.25, -1.0),
+            std::f32::consts::FRAC_PI_2,
+            settings.width as f32 / settings.height as f32,
+        );
+        let film = render::render(&Scene::cornell_box(), &camera, &settings);
+        let pixels = (film.width * film.height) as f32;
+        let mean = film.resolve().iter().map(|c| c.luminance()).sum::<f32>() / pixels;
+        let variance = (0..film.height)
+            .flat_map(|y| (0..film.width).map(move |x| (x, y)))
+            .map(|(x, y)| film.variance(x, y))
+            .sum::<f32>();
+        (mean, variance.sqrt() / pixels)
+    }
+
+    /// Both integrators estimate the same integral, so their images must
+    /// agree up to noise. The light tracing splats are not part of the
+    /// pixel variance, which the generous bound makes up for.
+    #[test]
+    fn matches_the_path_tracer() {
+        let (path, path_error) = mean_luminance("path", 1);
+        let (bdpt, bdpt_error) = mean_luminance("bdpt", 2);
+        let sigma = (path_error * path_error + bdpt_error * bdpt_error).sqrt();
+        assert!(
+            (path - bdpt).abs() < 5.0 * sigma,
+            "path tracer {} and bidirectional {} differ by more than 5 sigma ({})",
+            path,
+            bdpt,
+            sigma
+        );
+    }
+}
@@ -59,4 +59,59 @@ impl Camera {
                 .sub(self.origin),
         }
     }
+
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    /// Vector from the origin to the center of the image plane.
+    fn forward(&self) -> Vec3 {
+        self.lower_left
+            .add(self.horizontal.scale(0.5))
+            .add(self.vertical.scale(0.5))
+            .sub(self.origin)
+    }
+
+    /// Unit view direction, the normal of the image plane.
+    pub fn view_direction(&self) -> Vec3 {
+        self.forward().normalize()
+    }
+
+    /// Film coordinates of the ray from the origin along `direction`, the
+    /// inverse of `ray_from_uv`, if it passes through the image plane.
+    pub fn uv_from_direction(&self, direction: Vec3) -> Option<(f32, f32)> {
+        let forward = self.forward();
+        let along = direction.dot(forward);
+        if along <= 0.0 {
+            return None;
+        }
+        let on_plane = direction
+            .scale(forward.square_magnitude() / along)
+            .add(self.origin)
+            .sub(self.lower_left);
+        let u = on_plane.dot(self.horizontal) / self.horizontal.square_magnitude();
+        let v = on_plane.dot(self.vertical) / self.vertical.square_magnitude();
+        if (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) {
+            Some((u, v))
+        } else {
+            None
+        }
+    }
+
+    /// Importance emitted along the unit `direction` and the solid angle
+    /// density of `ray_from_uv` generating it from uniform film coordinates,
+    /// both zero outside the field of view. Normalized so that a camera ray
+    /// carries a throughput of one.
+    pub fn importance(&self, direction: Vec3) -> (f32, f32) {
+        if self.uv_from_direction(direction).is_none() {
+            return (0.0, 0.0);
+        }
+        let forward = self.forward();
+        let cos = direction.dot(forward.normalize());
+        // Area of the image plane moved to unit distance from the origin.
+        let area =
+            self.horizontal.magnitude() * self.vertical.magnitude() / forward.square_magnitude();
+        let pdf = 1.0 / (area * cos * cos * cos);
+        (pdf / cos, pdf)
+    }
 }
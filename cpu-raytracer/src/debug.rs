@@ -0,0 +1,136 @@
+//! Integrators for lookdev and regression checks: ambient occlusion and
+//! false color views of the first hit and of the path tracer's paths. They
+//! run through the same camera and tiles as the path tracer.
+//!
+//! There is no barycentric view, as the scene is built from analytic
+//! spheres and planes without any triangles.
+
+use crate::film::{heat_color, Aovs};
+use crate::integrator::{ray_color, record_first_hit, Integrator, SampleOutput, BOUNCES};
+use crate::lpe::PathRecord;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::cosine_hemisphere;
+use crate::scene::{Hit, Scene};
+use crate::stats;
+use crate::vec3::Vec3;
+
+pub enum DebugView {
+    /// World space normal at the first hit, mapped from [-1, 1].
+    Normal,
+    /// Surface coordinates at the first hit, repeating every unit.
+    Uv,
+    /// Distance to the first hit, white up close and fading with distance.
+    Depth,
+    /// Scattering events along a path tracer path, as a heatmap.
+    Bounces,
+    /// Intersection tests spent on a path tracer path, as a logarithmic
+    /// heatmap from the cost of `BOUNCES` plain bounces in blue to sixteen
+    /// times that in red.
+    Cost,
+}
+
+impl Integrator for DebugView {
+    /// Color of the view along the camera `ray`. Views of the first hit are
+    /// black where the ray escapes.
+    fn li(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        output: &mut SampleOutput,
+    ) -> Vec3 {
+        let aovs = &mut output.aovs;
+        match self {
+            DebugView::Bounces => {
+                let mut path = PathRecord::new();
+                ray_color(ray, scene, sampler, aovs, Some(&mut path));
+                let bounces = path.events.len() - 1;
+                display(heat_color(bounces as f32 / BOUNCES as f32))
+            }
+            DebugView::Cost => {
+                let start = stats::snapshot();
+                ray_color(ray, scene, sampler, aovs, None);
+                let tests = stats::snapshot().since(&start).intersection_tests();
+                let plain = BOUNCES * (scene.spheres.len() + scene.planes.len());
+                display(heat_color((tests as f32 / plain as f32).log2() / 4.0))
+            }
+            _ => {
+                let hit = match first_hit(ray, scene, aovs) {
+                    Some(hit) => hit,
+                    None => return Vec3::zero(),
+                };
+                let color = match self {
+                    DebugView::Normal => hit.normal.scale(0.5).add(Vec3::new(0.5, 0.5, 0.5)),
+                    DebugView::Uv => {
+                        let (u, v) = scene.uv(hit.object, ray.at(hit.t));
+                        Vec3::new(u - u.floor(), v - v.floor(), 0.0)
+                    }
+                    _ => {
+                        let depth = 1.0 / (1.0 + aovs.depth);
+                        Vec3::new(depth, depth, depth)
+                    }
+                };
+                display(color)
+            }
+        }
+    }
+}
+
+/// Undoes the gamma applied when the image is written, so that false
+/// colors show up as they were chosen.
+fn display(color: Vec3) -> Vec3 {
+    color.hadamard(color)
+}
+
+fn first_hit(ray: Ray, scene: &Scene, aovs: &mut Aovs) -> Option<Hit> {
+    let hit = scene.intersect(ray)?;
+    let material = scene.materials[hit.material as usize];
+    let id = Some(hit.material);
+    record_first_hit(
+        aovs,
+        ray,
+        hit.t,
+        material.albedo(),
+        hit.normal,
+        id,
+        hit.object,
+    );
+    Some(hit)
+}
+
+/// Fraction of the hemisphere above the first hit that is not occluded
+/// within `distance`, estimated with one cosine weighted ray. Escaping
+/// camera rays are white.
+pub struct AmbientOcclusion {
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        output: &mut SampleOutput,
+    ) -> Vec3 {
+        let hit = match first_hit(ray, scene, &mut output.aovs) {
+            Some(hit) => hit,
+            None => return Vec3::one(),
+        };
+        let normal = if hit.normal.dot(ray.direction) > 0.0 {
+            hit.normal.scale(-1.0)
+        } else {
+            hit.normal
+        };
+        let occlusion_ray = Ray {
+            origin: ray.at(hit.t),
+            direction: cosine_hemisphere(normal, sampler.get_2d()).direction,
+        };
+        stats::count(|s| s.shadow_rays += 1);
+        match scene.intersect(occlusion_ray) {
+            Some(hit) if hit.t < self.distance => Vec3::zero(),
+            _ => Vec3::one(),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Edge-avoiding à-trous wavelet denoiser from Dammertz et al., "Edge-Avoiding
+//! À-Trous Wavelet Transform for fast Global Illumination Filtering" (2010),
+//! with the luminance edge-stopping function driven by the per-pixel variance
+//! as in Schied et al., "Spatiotemporal Variance-Guided Filtering" (2017).
+
+use crate::film::{Aovs, Film};
+use crate::vec3::Vec3;
+
+/// Number of wavelet levels; the footprint doubles with each one, so five
+/// levels cover a 61 pixel wide neighbourhood.
+const ITERATIONS: usize = 5;
+
+const SIGMA_LUMINANCE: f32 = 4.0;
+const NORMAL_EXPONENT: f32 = 128.0;
+/// Depth tolerance relative to the depth of the center pixel, per pixel of
+/// filter step.
+const SIGMA_DEPTH: f32 = 0.02;
+const SIGMA_ALBEDO: f32 = 0.1;
+
+/// Cubic B-spline kernel taps.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Returns the denoised radiance of `film`, stored like `Film::resolve`.
+pub fn denoise(film: &Film) -> Vec<Vec3> {
+    let (width, height) = (film.width, film.height);
+    let features: Vec<Aovs> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| film.aovs(x, y))
+        .collect();
+
+    let variance = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| film.variance(x, y))
+        .collect();
+    filter(width, height, film.resolve(), variance, &features)
+}
+
+/// Filters `color`, whose per-pixel variance is `variance`, guided by the
+/// feature buffers in `features`. All are stored row by row.
+fn filter(
+    width: usize,
+    height: usize,
+    mut color: Vec<Vec3>,
+    mut variance: Vec<f32>,
+    features: &[Aovs],
+) -> Vec<Vec3> {
+    for level in 0..ITERATIONS {
+        let step = 1 << level;
+        let mut next_color = vec![Vec3::zero(); color.len()];
+        let mut next_variance = vec![0.0; variance.len()];
+
+        for y in 0..height {
+            for x in 0..width {
+                let p = y * width + x;
+                let luminance_p = color[p].luminance();
+                let sigma_l = SIGMA_LUMINANCE
+                    * blurred_variance(&variance, width, height, x, y).sqrt()
+                    + 1e-4;
+
+                let mut sum = Vec3::zero();
+                let mut sum_variance = 0.0;
+                let mut total = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    let qy = y as isize + (j as isize - 2) * step;
+                    if qy < 0 || qy >= height as isize {
+                        continue;
+                    }
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x as isize + (i as isize - 2) * step;
+                        if qx < 0 || qx >= width as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let w_l = (-(luminance_p - color[q].luminance()).abs() / sigma_l).exp();
+                        let w =
+                            kx * ky * w_l * edge_weight(&features[p], &features[q], step as f32);
+
+                        sum = sum.add(color[q].scale(w));
+                        sum_variance += w * w * variance[q];
+                        total += w;
+                    }
+                }
+
+                // The center tap always has a positive weight.
+                next_color[p] = sum.scale(1.0 / total);
+                next_variance[p] = sum_variance / (total * total);
+            }
+        }
+
+        color = next_color;
+        variance = next_variance;
+    }
+
+    color
+}
+
+/// Edge-stopping weight from the normal, depth and albedo buffers.
+fn edge_weight(p: &Aovs, q: &Aovs, step: f32) -> f32 {
+    let escaped_p = p.normal.square_magnitude() == 0.0;
+    let escaped_q = q.normal.square_magnitude() == 0.0;
+    if escaped_p || escaped_q {
+        return if escaped_p == escaped_q { 1.0 } else { 0.0 };
+    }
+
+    let w_n = p
+        .normal
+        .normalize()
+        .dot(q.normal.normalize())
+        .max(0.0)
+        .powf(NORMAL_EXPONENT);
+    let w_z = (-(p.depth - q.depth).abs() / (SIGMA_DEPTH * step * p.depth + 1e-4)).exp();
+    let w_a = (-p.albedo.sub(q.albedo).square_magnitude() / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+    w_n * w_z * w_a
+}
+
+/// Variance at (`x`, `y`) smoothed by a 3x3 Gaussian, so pixels whose
+/// samples happened to agree still accept their neighbours.
+fn blurred_variance(variance: &[f32], width: usize, height: usize, x: usize, y: usize) -> f32 {
+    const TAPS: [f32; 3] = [0.25, 0.5, 0.25];
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    for (j, ky) in TAPS.iter().enumerate() {
+        let qy = y as isize + j as isize - 1;
+        if qy < 0 || qy >= height as isize {
+            continue;
+        }
+        for (i, kx) in TAPS.iter().enumerate() {
+            let qx = x as isize + i as isize - 1;
+            if qx < 0 || qx >= width as isize {
+                continue;
+            }
+            sum += kx * ky * variance[qy as usize * width + qx as usize];
+            total += kx * ky;
+        }
+    }
+    sum / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{Pcg32, Rng};
+
+    const SIZE: usize = 32;
+
+    fn surface(normal: Vec3, albedo: Vec3) -> Aovs {
+        Aovs {
+            depth: 2.0,
+            normal,
+            albedo,
+            ..Aovs::none()
+        }
+    }
+
+    /// Grey noise of the given spread around 0.5, and its per-pixel
+    /// variance.
+    fn noise(spread: f32) -> (Vec<Vec3>, Vec<f32>) {
+        let mut rng = Pcg32::new(21, 0);
+        let color = (0..SIZE * SIZE)
+            .map(|_| {
+                let v = 0.5 + spread * rng.bi();
+                Vec3::new(v, v, v)
+            })
+            .collect();
+        (color, vec![spread * spread / 3.0; SIZE * SIZE])
+    }
+
+    fn variance(values: &[f32]) -> f32 {
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / values.len() as f32
+    }
+
+    #[test]
+    fn smooths_noise_on_a_flat_surface() {
+        let features = vec![surface(Vec3::new(0.0, 0.0, 1.0), Vec3::one()); SIZE * SIZE];
+        let (color, pixel_variance) = noise(0.2);
+        let before: Vec<f32> = color.iter().map(|c| c.x).collect();
+        let filtered = filter(SIZE, SIZE, color, pixel_variance, &features);
+        let after: Vec<f32> = filtered.iter().map(|c| c.x).collect();
+        assert!(
+            variance(&after) < 0.1 * variance(&before),
+            "variance went from {} to {}",
+            variance(&before),
+            variance(&after)
+        );
+    }
+
+    /// A step in the image that coincides with a step in the normal or the
+    /// albedo buffer must survive the filter, even though the noise around
+    /// it is as large as the step.
+    #[test]
+    fn keeps_edges_of_the_feature_buffers() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let side = Vec3::new(1.0, 0.0, 0.0);
+        let white = Vec3::one();
+        let red = Vec3::new(0.8, 0.1, 0.1);
+        let edges = [
+            (surface(up, white), surface(side, white)),
+            (surface(up, white), surface(up, red)),
+        ];
+        for (left, right) in edges.iter() {
+            let features: Vec<Aovs> = (0..SIZE * SIZE)
+                .map(|i| if i % SIZE < SIZE / 2 { *left } else { *right })
+                .collect();
+            let (mut color, pixel_variance) = noise(0.2);
+            for (i, c) in color.iter_mut().enumerate() {
+                if i % SIZE >= SIZE / 2 {
+                    *c = c.add(Vec3::new(0.3, 0.3, 0.3));
+                }
+            }
+            let filtered = filter(SIZE, SIZE, color, pixel_variance, &features);
+            let column = |x: usize| (0..SIZE).map(|y| filtered[y * SIZE + x].x).sum::<f32>();
+            let step = (column(SIZE / 2) - column(SIZE / 2 - 1)) / SIZE as f32;
+            assert!(step > 0.25, "edge blurred to a step of {}", step);
+        }
+    }
+}
@@ -0,0 +1,724 @@
+//! Rendering across processes over TCP. A coordinator splits every pass of
+//! a render into tiles and deals them out in batches to worker processes,
+//! which send back the float contents of the tiles. Tiles are merged in the
+//! same order as in a local render, so the image is identical to one.
+//!
+//! There is no scene file, so the coordinator sends its command line, from
+//! which each worker builds the scene and settings as `main` does. Volume
+//! grids are loaded by path and have to exist on every worker.
+//!
+//! Messages are a one byte tag followed by little endian fields. The
+//! coordinator sends a `JOB` with the arguments, then `BATCH`es of tile
+//! bounds with the samples taken and wanted per pixel. A worker answers the
+//! job with `READY` and its thread count, or `FAILED` and a message, and
+//! every batch with `TILES`: its statistics, then per tile the timing and
+//! the encoded film tile, then the splats added while rendering them. While
+//! rendering it sends a `HEARTBEAT` every second, so a worker that went
+//! away without closing the connection is noticed.
+//!
+//! A worker that fails is dropped and its batch is handed to the others.
+//! Once all workers are lost, the coordinator renders what is left itself.
+
+use crate::camera::Camera;
+use crate::film::{Film, FilmTile};
+use crate::integrator::{Integrator, Technique};
+use crate::options::Options;
+use crate::progress::Reporter;
+use crate::render::{self, Settings, Tile, TileTiming};
+use crate::scene::Scene;
+use crate::scheduler::TaskTiming;
+use crate::stats::Stats;
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Coordinator to worker.
+const JOB: u8 = 1;
+const BATCH: u8 = 2;
+// Worker to coordinator.
+const READY: u8 = 1;
+const FAILED: u8 = 2;
+const HEARTBEAT: u8 = 3;
+const TILES: u8 = 4;
+
+/// Limits on the lengths read from the network, so a bad peer cannot
+/// make the other side allocate or loop without bound.
+const MAX_STRING_LENGTH: usize = 64 * 1024;
+const MAX_ARGS: usize = 1024;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// A worker not heard of for this long is considered lost.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds the scene and camera a job's options describe.
+pub type SceneBuilder = fn(&Options) -> Result<(Scene, Camera), String>;
+
+/// Pixel bounds `[x_min, x_max)` by `[y_min, y_max)` of a tile.
+type Bounds = [usize; 4];
+
+fn bounds(tile: &Tile) -> Bounds {
+    [tile.x_min, tile.x_max, tile.y_min, tile.y_max]
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads a length prefix and rejects it if it is above `max`.
+fn read_length(r: &mut impl Read, max: usize, what: &str) -> io::Result<usize> {
+    let length = read_u32(r)? as usize;
+    if length > max {
+        return Err(invalid(format!("{} too long", what)));
+    }
+    Ok(length)
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![0; read_length(r, MAX_STRING_LENGTH, "string")?];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(w: &mut impl Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_string(w: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u32(w, value.len() as u32)?;
+    w.write_all(value.as_bytes())
+}
+
+fn stats_fields(s: &mut Stats) -> [&mut u64; 8] {
+    [
+        &mut s.primary_rays,
+        &mut s.shadow_rays,
+        &mut s.rays,
+        &mut s.sphere_tests,
+        &mut s.plane_tests,
+        &mut s.volume_bounds_tests,
+        &mut s.nonfinite_samples,
+        &mut s.clamped_samples,
+    ]
+}
+
+fn write_stats(w: &mut impl Write, stats: &Stats) -> io::Result<()> {
+    let mut stats = *stats;
+    for f in stats_fields(&mut stats) {
+        write_u64(w, *f)?;
+    }
+    Ok(())
+}
+
+fn read_stats(r: &mut impl Read) -> io::Result<Stats> {
+    let mut stats = Stats::default();
+    for f in stats_fields(&mut stats) {
+        *f = read_u64(r)?;
+    }
+    Ok(stats)
+}
+
+/// Serves the coordinators connecting to `listener` one after another,
+/// rendering their tiles on `threads` threads. Only returns if accepting a
+/// connection fails.
+pub fn serve(listener: TcpListener, threads: u32, build: SceneBuilder) -> io::Error {
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) => return e,
+        };
+        eprintln!("Coordinator {} connected", peer);
+        match work(&stream, threads, build) {
+            Ok(()) => eprintln!("Coordinator {} finished", peer),
+            Err(e) => eprintln!("Coordinator {} lost: {}", peer, e),
+        }
+    }
+}
+
+/// Renders the batches of a single coordinator until it disconnects.
+fn work(stream: &TcpStream, threads: u32, build: SceneBuilder) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+
+    if read_u8(&mut reader)? != JOB {
+        return Err(invalid("expected a job"));
+    }
+    let args = (0..read_length(&mut reader, MAX_ARGS, "argument list")?)
+        .map(|_| read_string(&mut reader))
+        .collect::<io::Result<Vec<String>>>()?;
+    let job = Options::parse(args).and_then(|options| {
+        let (scene, camera) = build(&options)?;
+        match options.settings.integrator.technique {
+            Technique::Sampled(create) => Ok((options.settings, scene, camera, create)),
+            Technique::Image(_) => Err(format!(
+                "the {} integrator does not render tiles",
+                options.settings.integrator.name
+            )),
+        }
+    });
+    let (mut settings, scene, camera, create) = match job {
+        Ok(job) => job,
+        Err(e) => {
+            writer.write_all(&[FAILED])?;
+            write_string(&mut writer, &e)?;
+            writer.flush()?;
+            return Err(invalid(e));
+        }
+    };
+    settings.threads = threads;
+    settings.progress = None;
+    let mut integrator = create(&camera, &settings);
+    integrator.preprocess(&scene);
+    let integrator = integrator.as_ref();
+
+    writer.write_all(&[READY])?;
+    write_u32(&mut writer, threads)?;
+    writer.flush()?;
+
+    // Only the splats of the film are used; tiles are sent back unmerged.
+    let names = settings
+        .light_paths
+        .iter()
+        .map(|e| e.name.clone())
+        .collect();
+    let (width, height) = (settings.width, settings.height);
+    let film = Film::new(width, height, settings.filter, names);
+    let mut taken = vec![0; width * height];
+    let mut wanted = vec![0; width * height];
+
+    loop {
+        match read_u8(&mut reader) {
+            Ok(BATCH) => {}
+            Ok(_) => return Err(invalid("expected a batch")),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut tiles = Vec::new();
+        // Tiles hold at least one pixel each.
+        for _ in 0..read_length(&mut reader, width * height, "batch")? {
+            let mut b = [0; 4];
+            for v in &mut b {
+                *v = read_u32(&mut reader)? as usize;
+            }
+            let [x_min, x_max, y_min, y_max] = b;
+            if x_min >= x_max || y_min >= y_max || x_max > width || y_max > height {
+                return Err(invalid("tile outside the image"));
+            }
+            for y in y_min..y_max {
+                for x in x_min..x_max {
+                    taken[y * width + x] = read_u32(&mut reader)? as usize;
+                    wanted[y * width + x] = read_u32(&mut reader)? as usize;
+                }
+            }
+            tiles.push(Tile {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+                film: film.tile(x_min, x_max, y_min, y_max),
+            });
+        }
+
+        let order: Vec<usize> = (0..tiles.len()).collect();
+        let progress = Reporter::new(&settings);
+        let timings = with_heartbeat(stream, || {
+            render::render_tiles(
+                &scene,
+                &camera,
+                &settings,
+                integrator,
+                &progress,
+                film.splats(),
+                &mut tiles,
+                &order,
+                &taken,
+                &wanted,
+            )
+        });
+
+        writer.write_all(&[TILES])?;
+        write_stats(&mut writer, &progress.finish())?;
+        for (tile, timing) in tiles.iter().zip(timings) {
+            write_u64(&mut writer, timing.start.as_micros() as u64)?;
+            write_u64(&mut writer, timing.duration.as_micros() as u64)?;
+            let words = tile.film.encode();
+            write_u32(&mut writer, words.len() as u32)?;
+            words.iter().try_for_each(|w| write_u32(&mut writer, *w))?;
+        }
+        let splats = film.splats().drain();
+        write_u32(&mut writer, splats.len() as u32)?;
+        for (i, sums) in splats {
+            write_u32(&mut writer, i as u32)?;
+            sums.iter().try_for_each(|s| write_u64(&mut writer, *s))?;
+        }
+        writer.flush()?;
+    }
+}
+
+/// Runs `f` while telling the coordinator that the worker is still alive.
+fn with_heartbeat<T>(stream: &TcpStream, f: impl FnOnce() -> T) -> T {
+    let (done, stopped) = mpsc::channel::<()>();
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut stream = stream;
+            while stopped.recv_timeout(HEARTBEAT_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+                if stream.write_all(&[HEARTBEAT]).is_err() {
+                    break;
+                }
+            }
+        });
+        let result = f();
+        drop(done);
+        result
+    })
+}
+
+/// Connection to a worker process that accepted the job.
+struct Worker {
+    address: String,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// Tiles sent per batch, one for each thread of the worker.
+    batch_size: usize,
+}
+
+/// A rendered tile with its timing relative to the batch it was sent in.
+type RenderedTile = (FilmTile, Duration, Duration);
+
+impl Worker {
+    fn connect(address: &str, args: &[String]) -> io::Result<Worker> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(WORKER_TIMEOUT))?;
+        let mut worker = Worker {
+            address: address.to_string(),
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            batch_size: 0,
+        };
+        worker.writer.write_all(&[JOB])?;
+        write_u32(&mut worker.writer, args.len() as u32)?;
+        for arg in args {
+            write_string(&mut worker.writer, arg)?;
+        }
+        worker.writer.flush()?;
+        if worker.next_message()? != READY {
+            return Err(invalid("expected the worker to be ready"));
+        }
+        worker.batch_size = read_u32(&mut worker.reader)?.max(1) as usize;
+        Ok(worker)
+    }
+
+    /// Tag of the next message other than a heartbeat. A failure message
+    /// is returned as an error.
+    fn next_message(&mut self) -> io::Result<u8> {
+        loop {
+            match read_u8(&mut self.reader)? {
+                HEARTBEAT => continue,
+                FAILED => return Err(io::Error::other(read_string(&mut self.reader)?)),
+                tag => return Ok(tag),
+            }
+        }
+    }
+
+    /// Has the worker render a batch of tiles and adds the splats of the
+    /// batch to `film`. Returns the tiles and the worker's statistics, or an
+    /// error without having touched the film.
+    fn render_batch(
+        &mut self,
+        film: &Film,
+        batch: &[Bounds],
+        taken: &[usize],
+        wanted: &[usize],
+    ) -> io::Result<(Vec<RenderedTile>, Stats)> {
+        let w = &mut self.writer;
+        w.write_all(&[BATCH])?;
+        write_u32(w, batch.len() as u32)?;
+        for &[x_min, x_max, y_min, y_max] in batch {
+            for v in [x_min, x_max, y_min, y_max] {
+                write_u32(w, v as u32)?;
+            }
+            for y in y_min..y_max {
+                for x in x_min..x_max {
+                    write_u32(w, taken[y * film.width + x] as u32)?;
+                    write_u32(w, wanted[y * film.width + x] as u32)?;
+                }
+            }
+        }
+        w.flush()?;
+
+        if self.next_message()? != TILES {
+            return Err(invalid("expected rendered tiles"));
+        }
+        let r = &mut self.reader;
+        let stats = read_stats(r)?;
+        let mut tiles = Vec::with_capacity(batch.len());
+        for &[x_min, x_max, y_min, y_max] in batch {
+            let start = Duration::from_micros(read_u64(r)?);
+            let duration = Duration::from_micros(read_u64(r)?);
+            let mut tile = film.tile(x_min, x_max, y_min, y_max);
+            let words = (0..read_length(r, tile.encoded_len(), "tile data")?)
+                .map(|_| read_u32(r))
+                .collect::<io::Result<Vec<u32>>>()?;
+            tile.decode(&words).map_err(invalid)?;
+            tiles.push((tile, start, duration));
+        }
+        let mut splats = Vec::new();
+        // Splats are drained at most once per pixel.
+        for _ in 0..read_length(r, film.width * film.height, "splat list")? {
+            let i = read_u32(r)? as usize;
+            if i >= film.width * film.height {
+                return Err(invalid("splat outside the image"));
+            }
+            splats.push((i, [read_u64(r)?, read_u64(r)?, read_u64(r)?]));
+        }
+        for (i, sums) in splats {
+            film.splats().add_drained(i, sums);
+        }
+        Ok((tiles, stats))
+    }
+
+    /// Sends batches of the pending tiles to the worker until none are
+    /// left. On failure the batch is put back for the other workers.
+    #[allow(clippy::too_many_arguments)]
+    fn render_batches(
+        &mut self,
+        index: usize,
+        state: &Mutex<Pass>,
+        changed: &Condvar,
+        film: &Film,
+        taken: &[usize],
+        wanted: &[usize],
+        progress: &Reporter,
+        start: Instant,
+    ) -> io::Result<()> {
+        loop {
+            let batch: Vec<(usize, Bounds)> = {
+                let mut pass = state.lock().unwrap();
+                loop {
+                    if !pass.pending.is_empty() {
+                        let n = self.batch_size.min(pass.pending.len());
+                        let indices: Vec<usize> = pass.pending.drain(..n).collect();
+                        break indices
+                            .into_iter()
+                            .map(|i| (i, bounds(&pass.tiles[i])))
+                            .collect();
+                    }
+                    if pass.remaining == 0 {
+                        return Ok(());
+                    }
+                    pass = changed.wait(pass).unwrap();
+                }
+            };
+
+            let sent = start.elapsed();
+            let bounds: Vec<Bounds> = batch.iter().map(|(_, b)| *b).collect();
+            match self.render_batch(film, &bounds, taken, wanted) {
+                Ok((tiles, stats)) => {
+                    let mut pass = state.lock().unwrap();
+                    for ((i, _), (tile, tile_start, duration)) in batch.iter().zip(tiles) {
+                        pass.tiles[*i].film = tile;
+                        pass.timings[*i] = Some(TaskTiming {
+                            thread: index,
+                            start: sent + tile_start,
+                            duration,
+                        });
+                    }
+                    pass.remaining -= batch.len();
+                    drop(pass);
+                    changed.notify_all();
+
+                    let samples = bounds.iter().map(|&[x_min, x_max, y_min, y_max]| {
+                        (y_min..y_max)
+                            .flat_map(|y| &wanted[y * film.width + x_min..y * film.width + x_max])
+                            .sum::<usize>()
+                    });
+                    progress.advance(samples.sum::<usize>() as u64, &stats);
+                }
+                Err(e) => {
+                    let mut pass = state.lock().unwrap();
+                    for (i, _) in batch.iter().rev() {
+                        pass.pending.push_front(*i);
+                    }
+                    drop(pass);
+                    changed.notify_all();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// The workers of a distributed render. Lost workers stay lost for the
+/// remaining passes.
+pub struct Coordinator {
+    workers: Vec<Option<Worker>>,
+}
+
+/// Tiles of a pass shared by the threads talking to the workers.
+struct Pass {
+    /// Tiles waiting for a worker, by index, the next one first.
+    pending: VecDeque<usize>,
+    /// Tiles not rendered yet, including those being rendered.
+    remaining: usize,
+    tiles: Vec<Tile>,
+    timings: Vec<Option<TaskTiming>>,
+}
+
+impl Coordinator {
+    /// Connects to the workers at `addresses` and sends them the job given
+    /// by the command line `args`. Workers that cannot be reached or reject
+    /// the job are left out, but at least one has to accept it.
+    pub fn connect(addresses: &[String], args: &[String]) -> Result<Coordinator, String> {
+        let workers: Vec<Option<Worker>> = addresses
+            .iter()
+            .map(|address| match Worker::connect(address, args) {
+                Ok(worker) => Some(worker),
+                Err(e) => {
+                    eprintln!("Skipping worker {}: {}", address, e);
+                    None
+                }
+            })
+            .collect();
+        if workers.iter().all(Option::is_none) {
+            return Err("--workers: none of the workers accepted the job".to_string());
+        }
+        Ok(Coordinator { workers })
+    }
+
+    /// Samples a pass like `render::render`'s, with the tiles rendered by
+    /// the workers. The thread of a tile timing is the index of the worker
+    /// that rendered it.
+    #[allow(clippy::too_many_arguments)]
+    fn render_pass(
+        &mut self,
+        scene: &Scene,
+        camera: &Camera,
+        settings: &Settings,
+        integrator: &dyn Integrator,
+        progress: &Reporter,
+        film: &mut Film,
+        taken: &[usize],
+        wanted: &[usize],
+        pass: usize,
+    ) {
+        progress.add_work(wanted.iter().sum::<usize>() as u64);
+        let (tiles, order) = render::plan_tiles(settings, film, wanted);
+        let state = Mutex::new(Pass {
+            pending: order.into(),
+            remaining: tiles.len(),
+            timings: vec![None; tiles.len()],
+            tiles,
+        });
+        let changed = Condvar::new();
+        let start = Instant::now();
+
+        let shared_film = &*film;
+        std::thread::scope(|scope| {
+            for (index, slot) in self.workers.iter_mut().enumerate() {
+                if slot.is_none() {
+                    continue;
+                }
+                let (state, changed) = (&state, &changed);
+                scope.spawn(move || {
+                    let worker = slot.as_mut().unwrap();
+                    let result = worker.render_batches(
+                        index,
+                        state,
+                        changed,
+                        shared_film,
+                        taken,
+                        wanted,
+                        progress,
+                        start,
+                    );
+                    if let Err(e) = result {
+                        eprintln!("Lost worker {}: {}", worker.address, e);
+                        *slot = None;
+                    }
+                });
+            }
+        });
+
+        let mut state = state.into_inner().unwrap();
+        if !state.pending.is_empty() {
+            eprintln!(
+                "All workers lost, rendering the remaining {} tiles locally",
+                state.pending.len()
+            );
+            let order: Vec<usize> = state.pending.drain(..).collect();
+            let offset = start.elapsed();
+            let timings = render::render_tiles(
+                scene,
+                camera,
+                settings,
+                integrator,
+                progress,
+                film.splats(),
+                &mut state.tiles,
+                &order,
+                taken,
+                wanted,
+            );
+            for i in order {
+                state.timings[i] = Some(TaskTiming {
+                    thread: self.workers.len() + timings[i].thread,
+                    start: offset + timings[i].start,
+                    duration: timings[i].duration,
+                });
+            }
+        }
+
+        // Merge in tile order, as a local render does.
+        for (t, timing) in state.tiles.iter().zip(state.timings) {
+            film.merge(&t.film);
+            film.tile_timings.push(TileTiming {
+                pass,
+                x_min: t.x_min,
+                x_max: t.x_max,
+                y_min: t.y_min,
+                y_max: t.y_max,
+                timing: timing.unwrap(),
+            });
+        }
+    }
+}
+
+/// Renders like `render::render` with the tiles of every pass rendered by
+/// the coordinator's workers. Only integrators that render tiles can be
+/// distributed.
+pub fn render(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &Settings,
+    coordinator: &mut Coordinator,
+) -> Film {
+    let mut integrator = match settings.integrator.technique {
+        Technique::Sampled(create) => create(camera, settings),
+        Technique::Image(_) => panic!(
+            "the {} integrator does not render tiles",
+            settings.integrator.name
+        ),
+    };
+    // Only used if every worker is lost.
+    integrator.preprocess(scene);
+    let integrator = integrator.as_ref();
+
+    render::render_passes(settings, |progress, film, taken, wanted, pass| {
+        coordinator.render_pass(
+            scene, camera, settings, integrator, progress, film, taken, wanted, pass,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Vec3;
+
+    fn cornell_box(options: &Options) -> Result<(Scene, Camera), String> {
+        let camera = Camera::look_at(
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.0, -0.25, -1.0),
+            std::f32::consts::FRAC_PI_2,
+            options.settings.width as f32 / options.settings.height as f32,
+        );
+        Ok((Scene::cornell_box(), camera))
+    }
+
+    fn spawn_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || serve(listener, 2, cornell_box));
+        address
+    }
+
+    /// A worker that accepts the job, then disconnects on its first batch.
+    fn spawn_failing_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                assert_eq!(read_u8(&mut stream).unwrap(), JOB);
+                for _ in 0..read_u32(&mut stream).unwrap() {
+                    read_string(&mut stream).unwrap();
+                }
+                stream.write_all(&[READY]).unwrap();
+                write_u32(&mut stream, 2).unwrap();
+                let _ = read_u8(&mut stream);
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn lengths_from_the_network_are_bounded() {
+        let mut message = Vec::new();
+        write_u32(&mut message, MAX_STRING_LENGTH as u32 + 1).unwrap();
+        let error = read_string(&mut message.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "string too long");
+
+        let mut message = Vec::new();
+        write_string(&mut message, "--width").unwrap();
+        assert_eq!(read_string(&mut message.as_slice()).unwrap(), "--width");
+    }
+
+    #[test]
+    fn distributed_render_matches_local_render_despite_lost_worker() {
+        let args = "--width 40 --height 24 --samples 2 --tile-size 8 --filter gaussian \
+                    --integrator bdpt --adaptive-passes 1 --threads 2";
+        let options = Options::parse(args.split_whitespace().map(String::from)).unwrap();
+        let (scene, camera) = cornell_box(&options).unwrap();
+        let local = render::render(&scene, &camera, &options.settings);
+
+        let workers = [spawn_failing_worker(), spawn_worker()];
+        let mut coordinator = Coordinator::connect(&workers, &options.worker_args).unwrap();
+        let distributed = render(&scene, &camera, &options.settings, &mut coordinator);
+
+        assert!(coordinator.workers[0].is_none());
+        assert!(coordinator.workers[1].is_some());
+        for y in 0..local.height {
+            for x in 0..local.width {
+                let (a, b) = (local.pixel(x, y), distributed.pixel(x, y));
+                assert_eq!(
+                    [a.x.to_bits(), a.y.to_bits(), a.z.to_bits()],
+                    [b.x.to_bits(), b.y.to_bits(), b.z.to_bits()],
+                    "pixel ({}, {}) differs",
+                    x,
+                    y
+                );
+                assert_eq!(local.sample_count(x, y), distributed.sample_count(x, y));
+            }
+        }
+        assert_eq!(local.stats, distributed.stats);
+        assert_eq!(local.tile_timings.len(), distributed.tile_timings.len());
+    }
+}
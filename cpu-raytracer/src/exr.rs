@@ -0,0 +1,144 @@
+//! Minimal OpenEXR writer: a single part, uncompressed scanline image with
+//! 32-bit float channels. Channel names may contain layer prefixes such as
+//! `albedo.R`, which compositing tools present as separate layers.
+
+use std::io::{self, Write};
+
+pub struct Channel {
+    pub name: String,
+    /// `width * height` values, top row first.
+    pub values: Vec<f32>,
+}
+
+const MAGIC: u32 = 20000630;
+const VERSION: u32 = 2;
+/// Version flag for attribute and channel names longer than 31 bytes.
+const LONG_NAMES: u32 = 0x400;
+/// Longest name allowed with the `LONG_NAMES` flag.
+const MAX_NAME_LENGTH: usize = 255;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+pub fn write<W: Write>(
+    w: &mut W,
+    width: usize,
+    height: usize,
+    channels: &[Channel],
+) -> io::Result<()> {
+    // Readers expect the channel list, and the channel data within each
+    // scanline, in alphabetical order.
+    let mut channels: Vec<&Channel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let longest = channels.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    if longest > MAX_NAME_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("EXR channel names are limited to {} bytes", MAX_NAME_LENGTH),
+        ));
+    }
+    // The attribute names below are all short.
+    let version = if longest > 31 {
+        VERSION | LONG_NAMES
+    } else {
+        VERSION
+    };
+
+    let mut list = Vec::new();
+    for c in &channels {
+        list.extend_from_slice(c.name.as_bytes());
+        list.push(0);
+        list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling.
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&version.to_le_bytes());
+    attribute(&mut header, "channels", "chlist", &list);
+    attribute(&mut header, "compression", "compression", &[0]);
+    attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+    attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // One block per scanline: y, byte count, then each channel's row.
+    let line_bytes = width * channels.len() * 4;
+    let block_bytes = 8 + line_bytes;
+    let table_start = header.len() + height * 8;
+    w.write_all(&header)?;
+    for y in 0..height {
+        let offset = (table_start + y * block_bytes) as u64;
+        w.write_all(&offset.to_le_bytes())?;
+    }
+
+    let mut block = Vec::with_capacity(block_bytes);
+    for y in 0..height {
+        block.clear();
+        block.extend_from_slice(&(y as i32).to_le_bytes());
+        block.extend_from_slice(&(line_bytes as i32).to_le_bytes());
+        for c in &channels {
+            for v in &c.values[y * width..(y + 1) * width] {
+                block.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        w.write_all(&block)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(name: &str) -> io::Result<u32> {
+        let channel = Channel {
+            name: name.to_string(),
+            values: vec![0.5; 4],
+        };
+        let mut file = Vec::new();
+        write(&mut file, 2, 2, &[channel])?;
+        Ok(u32::from_le_bytes([file[4], file[5], file[6], file[7]]))
+    }
+
+    #[test]
+    fn long_channel_names_set_the_version_flag() {
+        assert_eq!(version("albedo.R").unwrap(), VERSION);
+        assert_eq!(version(&"a".repeat(31)).unwrap(), VERSION);
+        assert_eq!(version(&"a".repeat(32)).unwrap(), VERSION | LONG_NAMES);
+        assert!(version(&"a".repeat(256)).is_err());
+    }
+}
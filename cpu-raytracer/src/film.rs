@@ -0,0 +1,636 @@
+use crate::filter::Filter;
+use crate::math::clamp01;
+use crate::render::TileTiming;
+use crate::stats::Stats;
+use crate::vec3::Vec3;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Arbitrary output variables written by the integrator for each sample.
+/// The first hit properties also guide the denoiser.
+#[derive(Copy, Clone)]
+pub struct Aovs {
+    /// Distance from the camera along the ray to the first hit.
+    pub depth: f32,
+    /// World space normal at the first hit.
+    pub normal: Vec3,
+    pub albedo: Vec3,
+    /// Light from emitters and the sky seen directly by the camera.
+    pub emission: Vec3,
+    /// Light reaching the camera after a single bounce.
+    pub direct: Vec3,
+    /// Light reaching the camera after two or more bounces.
+    pub indirect: Vec3,
+    pub material_id: Option<u8>,
+    pub object_id: Option<usize>,
+}
+
+impl Aovs {
+    pub const fn none() -> Aovs {
+        Aovs {
+            depth: 0.0,
+            normal: Vec3::zero(),
+            albedo: Vec3::zero(),
+            emission: Vec3::zero(),
+            direct: Vec3::zero(),
+            indirect: Vec3::zero(),
+            material_id: None,
+            object_id: None,
+        }
+    }
+
+    /// Sums the continuous variables. Ids cannot be averaged, so the first
+    /// sample to record one keeps it.
+    fn accumulate(&mut self, rhs: &Aovs) {
+        self.depth += rhs.depth;
+        self.normal = self.normal.add(rhs.normal);
+        self.albedo = self.albedo.add(rhs.albedo);
+        self.emission = self.emission.add(rhs.emission);
+        self.direct = self.direct.add(rhs.direct);
+        self.indirect = self.indirect.add(rhs.indirect);
+        self.material_id = self.material_id.or(rhs.material_id);
+        self.object_id = self.object_id.or(rhs.object_id);
+    }
+
+    fn scale(&self, scalar: f32) -> Aovs {
+        Aovs {
+            depth: self.depth * scalar,
+            normal: self.normal.scale(scalar),
+            albedo: self.albedo.scale(scalar),
+            emission: self.emission.scale(scalar),
+            direct: self.direct.scale(scalar),
+            indirect: self.indirect.scale(scalar),
+            ..*self
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Pixel {
+    color: Vec3,
+    weight: f32,
+    /// Unfiltered sum of the AOVs of every sample taken in the pixel.
+    aovs: Aovs,
+    /// Running luminance statistics (Welford) of the unfiltered samples
+    /// taken inside this pixel, used to drive adaptive sampling.
+    samples: u32,
+    mean: f32,
+    m2: f32,
+}
+
+/// Words a pixel takes up in `FilmTile::encode`.
+const PIXEL_WORDS: usize = 25;
+
+const EMPTY: Pixel = Pixel {
+    color: Vec3::zero(),
+    weight: 0.0,
+    aovs: Aovs::none(),
+    samples: 0,
+    mean: 0.0,
+    m2: 0.0,
+};
+
+impl Pixel {
+    fn add_statistics(&mut self, value: f32) {
+        self.samples += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Combines the statistics of two disjoint sets of samples (Chan et al.).
+    fn merge_statistics(&mut self, other: &Pixel) {
+        if other.samples == 0 {
+            return;
+        }
+        let n_a = self.samples as f32;
+        let n_b = other.samples as f32;
+        let n = n_a + n_b;
+        let delta = other.mean - self.mean;
+        self.mean += delta * n_b / n;
+        self.m2 += other.m2 + delta * delta * n_a * n_b / n;
+        self.samples += other.samples;
+    }
+}
+
+/// Pixel bounds `[x_min, x_max)` by `[y_min, y_max)` of a part of the film,
+/// with rows counted from the bottom like the film's.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Crop {
+    pub x_min: usize,
+    pub x_max: usize,
+    pub y_min: usize,
+    pub y_max: usize,
+}
+
+impl Crop {
+    /// Converts the columns `[x0, x1)` and rows `[y0, y1)` of an image with
+    /// its first row at the top, as written to files.
+    pub fn from_image_rect(x0: usize, y0: usize, x1: usize, y1: usize, height: usize) -> Crop {
+        Crop {
+            x_min: x0,
+            x_max: x1,
+            y_min: height - y1,
+            y_max: height - y0,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.x_max - self.x_min
+    }
+
+    pub fn height(&self) -> usize {
+        self.y_max - self.y_min
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x_min..self.x_max).contains(&x) && (self.y_min..self.y_max).contains(&y)
+    }
+}
+
+/// Accumulates filtered radiance samples for a whole image. Rows are stored
+/// bottom to top, matching the camera's `v` axis.
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    filter: Filter,
+    pixels: Vec<Pixel>,
+    /// Names of the light path expression buffers.
+    light_paths: Vec<String>,
+    /// Filtered radiance of each light path buffer, interleaved per pixel.
+    light_path_colors: Vec<Vec3>,
+    splats: SplatBuffer,
+    /// Total number of samples taken, which splats are normalized by.
+    samples: u64,
+    /// Filled in by `render` for every tile of every pass.
+    pub tile_timings: Vec<TileTiming>,
+    pub stats: Stats,
+}
+
+/// Unfiltered image that light tracing strategies add to from any thread.
+/// Values are summed in fixed point, so the result does not depend on the
+/// order threads splat in.
+pub struct SplatBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<[AtomicU64; 3]>,
+}
+
+const SPLAT_SCALE: f32 = (1u32 << 24) as f32;
+
+impl SplatBuffer {
+    fn new(width: usize, height: usize) -> SplatBuffer {
+        SplatBuffer {
+            width,
+            height,
+            pixels: (0..width * height)
+                .map(|_| [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)])
+                .collect(),
+        }
+    }
+
+    /// Adds `color` to the pixel containing film position (`x`, `y`).
+    /// Negative and non-finite values are dropped.
+    pub fn add(&self, x: f32, y: f32, color: Vec3) {
+        if !(x >= 0.0 && y >= 0.0) {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let pixel = &self.pixels[y * self.width + x];
+        for (p, c) in pixel.iter().zip([color.x, color.y, color.z]) {
+            if c.is_finite() && c > 0.0 {
+                p.fetch_add((c * SPLAT_SCALE) as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Empties the buffer, returning the fixed point sums of every pixel
+    /// that was splatted into by its index.
+    pub fn drain(&self) -> Vec<(usize, [u64; 3])> {
+        let mut splats = Vec::new();
+        for (i, p) in self.pixels.iter().enumerate() {
+            let sums = [0, 1, 2].map(|c| p[c].swap(0, Ordering::Relaxed));
+            if sums != [0; 3] {
+                splats.push((i, sums));
+            }
+        }
+        splats
+    }
+
+    /// Adds fixed point sums taken from another buffer by `drain`.
+    pub fn add_drained(&self, index: usize, sums: [u64; 3]) {
+        for (p, s) in self.pixels[index].iter().zip(sums) {
+            p.fetch_add(s, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> Vec3 {
+        let p = &self.pixels[y * self.width + x];
+        let channel = |i: usize| p[i].load(Ordering::Relaxed) as f32 / SPLAT_SCALE;
+        Vec3::new(channel(0), channel(1), channel(2))
+    }
+}
+
+/// Part of the film written by a single tile. It covers the tile's pixels
+/// plus a border of the filter radius, so samples near the tile edges are
+/// splatted into neighbouring tiles when the tile is merged back.
+pub struct FilmTile {
+    x_min: usize,
+    x_max: usize,
+    y_min: usize,
+    y_max: usize,
+    filter: Filter,
+    pixels: Vec<Pixel>,
+    light_paths: usize,
+    light_path_colors: Vec<Vec3>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter, light_paths: Vec<String>) -> Film {
+        Film {
+            width,
+            height,
+            filter,
+            pixels: vec![EMPTY; width * height],
+            light_path_colors: vec![Vec3::zero(); width * height * light_paths.len()],
+            light_paths,
+            splats: SplatBuffer::new(width, height),
+            samples: 0,
+            tile_timings: Vec::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Creates a tile for samples inside the pixel bounds `[x_min, x_max)`
+    /// and `[y_min, y_max)`.
+    pub fn tile(&self, x_min: usize, x_max: usize, y_min: usize, y_max: usize) -> FilmTile {
+        let border = (self.filter.radius() - 0.5).ceil().max(0.0) as usize;
+        let x_min = x_min.saturating_sub(border);
+        let y_min = y_min.saturating_sub(border);
+        let x_max = (x_max + border).min(self.width);
+        let y_max = (y_max + border).min(self.height);
+        FilmTile {
+            x_min,
+            x_max,
+            y_min,
+            y_max,
+            filter: self.filter,
+            pixels: vec![EMPTY; (x_max - x_min) * (y_max - y_min)],
+            light_paths: self.light_paths.len(),
+            light_path_colors: vec![
+                Vec3::zero();
+                (x_max - x_min) * (y_max - y_min) * self.light_paths.len()
+            ],
+        }
+    }
+
+    pub fn merge(&mut self, tile: &FilmTile) {
+        let tile_width = tile.x_max - tile.x_min;
+        for y in tile.y_min..tile.y_max {
+            for x in tile.x_min..tile.x_max {
+                let i = (y - tile.y_min) * tile_width + (x - tile.x_min);
+                let src = tile.pixels[i];
+                let dst = &mut self.pixels[y * self.width + x];
+                dst.color = dst.color.add(src.color);
+                dst.weight += src.weight;
+                dst.aovs.accumulate(&src.aovs);
+                dst.merge_statistics(&src);
+                self.samples += src.samples as u64;
+
+                let n = tile.light_paths;
+                let src = &tile.light_path_colors[i * n..(i + 1) * n];
+                let dst = &mut self.light_path_colors[(y * self.width + x) * n..][..n];
+                for (d, s) in dst.iter_mut().zip(src) {
+                    *d = d.add(*s);
+                }
+            }
+        }
+    }
+
+    /// Counts samples that only splatted, for integrators that do not add
+    /// samples through tiles.
+    pub fn count_samples(&mut self, samples: u64) {
+        self.samples += samples;
+    }
+
+    pub fn splats(&self) -> &SplatBuffer {
+        &self.splats
+    }
+
+    /// Empties every pixel outside `crop`, including the filtered samples
+    /// from inside it and the splats that landed there.
+    pub fn clear_outside(&mut self, crop: &Crop) {
+        let n = self.light_paths.len();
+        for y in 0..self.height {
+            for x in (0..self.width).filter(|x| !crop.contains(*x, y)) {
+                let i = y * self.width + x;
+                self.pixels[i] = EMPTY;
+                for c in &mut self.light_path_colors[i * n..(i + 1) * n] {
+                    *c = Vec3::zero();
+                }
+                for c in &self.splats.pixels[i] {
+                    c.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Shrinks the film to the pixels inside `crop`. Pixel coordinates of
+    /// the tile timings stay those of the full film.
+    pub fn crop(self, crop: &Crop) -> Film {
+        let names = self.light_paths.clone();
+        let mut film = Film::new(crop.width(), crop.height(), self.filter, names);
+        // Splats are normalized by the pixel count of the film, so they are
+        // rescaled to keep the same estimate on the smaller film.
+        let splat_scale = (self.width * self.height) as f64 / (film.width * film.height) as f64;
+        let n = self.light_paths.len();
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let src = (y + crop.y_min) * self.width + x + crop.x_min;
+                let dst = y * film.width + x;
+                film.pixels[dst] = self.pixels[src];
+                film.light_path_colors[dst * n..(dst + 1) * n]
+                    .copy_from_slice(&self.light_path_colors[src * n..(src + 1) * n]);
+                for (d, s) in film.splats.pixels[dst].iter().zip(&self.splats.pixels[src]) {
+                    let value = s.load(Ordering::Relaxed) as f64 * splat_scale;
+                    d.store(value as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        film.samples = self.samples;
+        film.stats = self.stats;
+        film.tile_timings = self.tile_timings;
+        film
+    }
+
+    /// Filtered linear radiance of the pixel at (`x`, `y`), including the
+    /// light splatted into it.
+    pub fn pixel(&self, x: usize, y: usize) -> Vec3 {
+        let p = self.pixels[y * self.width + x];
+        let color = if p.weight > 0.0 {
+            p.color.scale(1.0 / p.weight)
+        } else {
+            Vec3::zero()
+        };
+
+        // Every camera sample traces one light path, so splats estimate the
+        // image scaled by the average number of samples per pixel.
+        if self.samples == 0 {
+            return color;
+        }
+        let scale = (self.width * self.height) as f32 / self.samples as f32;
+        color.add(self.splats.get(x, y).scale(scale))
+    }
+
+    /// Filtered linear radiance of every pixel, in storage order.
+    pub fn resolve(&self) -> Vec<Vec3> {
+        (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.pixel(x, y))
+            .collect()
+    }
+
+    pub fn light_path_names(&self) -> &[String] {
+        &self.light_paths
+    }
+
+    /// Filtered radiance of light path buffer `index`, in storage order.
+    pub fn resolve_light_path(&self, index: usize) -> Vec<Vec3> {
+        let n = self.light_paths.len();
+        self.pixels
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                if p.weight > 0.0 {
+                    self.light_path_colors[i * n + index].scale(1.0 / p.weight)
+                } else {
+                    Vec3::zero()
+                }
+            })
+            .collect()
+    }
+
+    /// Average AOVs of the samples taken inside the pixel at (`x`, `y`).
+    pub fn aovs(&self, x: usize, y: usize) -> Aovs {
+        let p = self.pixels[y * self.width + x];
+        p.aovs.scale(1.0 / p.samples.max(1) as f32)
+    }
+
+    /// Estimated variance of the pixel's mean luminance.
+    pub fn variance(&self, x: usize, y: usize) -> f32 {
+        let p = self.pixels[y * self.width + x];
+        if p.samples < 2 {
+            return 0.0;
+        }
+        p.m2 / ((p.samples - 1) * p.samples) as f32
+    }
+
+    /// Number of samples taken inside the pixel at (`x`, `y`).
+    pub fn sample_count(&self, x: usize, y: usize) -> u32 {
+        self.pixels[y * self.width + x].samples
+    }
+
+    /// Standard error of the pixel's mean luminance relative to the mean.
+    /// The mean is offset slightly so dark pixels are not oversampled.
+    pub fn relative_error(&self, x: usize, y: usize) -> f32 {
+        let p = self.pixels[y * self.width + x];
+        if p.samples < 2 {
+            return f32::MAX;
+        }
+        self.variance(x, y).sqrt() / (p.mean.abs() + 0.01)
+    }
+
+    /// False color image of the per-pixel sample counts, from blue for the
+    /// fewest samples to red for the most, top row first.
+    pub fn heatmap_rgb8(&self) -> Vec<u8> {
+        let (min, max) = self.pixels.iter().fold((u32::MAX, 0), |(lo, hi), p| {
+            (lo.min(p.samples), hi.max(p.samples))
+        });
+        let range = (max - min).max(1) as f32;
+
+        let mut data = vec![0; self.width * self.height * 3];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let t = (self.sample_count(x, y) - min) as f32 / range;
+                let color = heat_color(t);
+                let i = ((self.height - y - 1) * self.width + x) * 3;
+                data[i] = (color.x * 255.0) as u8;
+                data[i + 1] = (color.y * 255.0) as u8;
+                data[i + 2] = (color.z * 255.0) as u8;
+            }
+        }
+        data
+    }
+
+    /// Resolves the film to gamma corrected 8-bit RGB, top row first.
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        encode_rgb8(self.width, self.height, &self.resolve())
+    }
+}
+
+/// False color ramp from blue at 0 over green to red at 1.
+pub fn heat_color(t: f32) -> Vec3 {
+    let t = clamp01(t);
+    if t < 0.5 {
+        Vec3::new(0.0, 0.0, 1.0).lerp(Vec3::new(0.0, 1.0, 0.0), t * 2.0)
+    } else {
+        Vec3::new(0.0, 1.0, 0.0).lerp(Vec3::new(1.0, 0.0, 0.0), t * 2.0 - 1.0)
+    }
+}
+
+/// Copies the pixels inside `crop` from `image` to `base`, both 8-bit RGB
+/// images of the film's size with the top row first.
+pub fn paste_rgb8(base: &mut [u8], image: &[u8], width: usize, height: usize, crop: &Crop) {
+    for y in crop.y_min..crop.y_max {
+        let row = (height - y - 1) * width;
+        let range = (row + crop.x_min) * 3..(row + crop.x_max) * 3;
+        base[range.clone()].copy_from_slice(&image[range]);
+    }
+}
+
+/// Gamma corrects linear radiance stored bottom row first into 8-bit RGB,
+/// top row first.
+pub fn encode_rgb8(width: usize, height: usize, colors: &[Vec3]) -> Vec<u8> {
+    let mut data = vec![0; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            let color = colors[y * width + x];
+            let i = ((height - y - 1) * width + x) * 3;
+            data[i] = (clamp01(color.x.max(0.0).sqrt()) * 255.0) as u8;
+            data[i + 1] = (clamp01(color.y.max(0.0).sqrt()) * 255.0) as u8;
+            data[i + 2] = (clamp01(color.z.max(0.0).sqrt()) * 255.0) as u8;
+        }
+    }
+    data
+}
+
+impl FilmTile {
+    /// Splats `color` sampled at continuous film position (`x`, `y`) into
+    /// every pixel within the filter radius, and records it in the variance
+    /// statistics of `pixel`, the pixel the sample was taken for.
+    /// `light_paths` holds the sample's radiance for each light path buffer.
+    pub fn add_sample(
+        &mut self,
+        pixel: (usize, usize),
+        x: f32,
+        y: f32,
+        color: Vec3,
+        light_paths: &[Vec3],
+    ) {
+        let tile_width = self.x_max - self.x_min;
+        self.pixels[(pixel.1 - self.y_min) * tile_width + (pixel.0 - self.x_min)]
+            .add_statistics(color.luminance());
+
+        let radius = self.filter.radius();
+        let x0 = ((x - 0.5 - radius).ceil().max(self.x_min as f32)) as usize;
+        let y0 = ((y - 0.5 - radius).ceil().max(self.y_min as f32)) as usize;
+        let x1 = ((x - 0.5 + radius).floor() + 1.0).min(self.x_max as f32) as usize;
+        let y1 = ((y - 0.5 + radius).floor() + 1.0).min(self.y_max as f32) as usize;
+
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let weight = self
+                    .filter
+                    .evaluate(px as f32 + 0.5 - x, py as f32 + 0.5 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let p = &mut self.pixels[(py - self.y_min) * tile_width + (px - self.x_min)];
+                p.color = p.color.add(color.scale(weight));
+                p.weight += weight;
+
+                let n = self.light_paths;
+                let i = (py - self.y_min) * tile_width + (px - self.x_min);
+                for (d, s) in self.light_path_colors[i * n..][..n]
+                    .iter_mut()
+                    .zip(light_paths)
+                {
+                    *d = d.add(s.scale(weight));
+                }
+            }
+        }
+    }
+
+    /// Number of words `encode` returns.
+    pub fn encoded_len(&self) -> usize {
+        self.pixels.len() * PIXEL_WORDS + self.light_path_colors.len() * 3
+    }
+
+    /// The tile's contents as plain words, bit for bit, for sending it to
+    /// another process.
+    pub fn encode(&self) -> Vec<u32> {
+        let mut words = Vec::with_capacity(self.encoded_len());
+        let vec3 = |words: &mut Vec<u32>, v: Vec3| {
+            words.extend([v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]);
+        };
+        for p in &self.pixels {
+            vec3(&mut words, p.color);
+            words.push(p.weight.to_bits());
+            let a = &p.aovs;
+            words.push(a.depth.to_bits());
+            for v in [a.normal, a.albedo, a.emission, a.direct, a.indirect] {
+                vec3(&mut words, v);
+            }
+            words.push(a.material_id.map_or(u32::MAX, u32::from));
+            words.push(a.object_id.map_or(u32::MAX, |id| id as u32));
+            words.extend([p.samples, p.mean.to_bits(), p.m2.to_bits()]);
+        }
+        for c in &self.light_path_colors {
+            vec3(&mut words, *c);
+        }
+        words
+    }
+
+    /// Replaces the contents with those `encode` returned for a tile of
+    /// the same bounds.
+    pub fn decode(&mut self, words: &[u32]) -> Result<(), String> {
+        let expected = self.encoded_len();
+        if words.len() != expected {
+            return Err(format!(
+                "expected {} words of tile data, got {}",
+                expected,
+                words.len()
+            ));
+        }
+        fn float(words: &mut impl Iterator<Item = u32>) -> f32 {
+            f32::from_bits(words.next().unwrap())
+        }
+        fn vec3(words: &mut impl Iterator<Item = u32>) -> Vec3 {
+            Vec3::new(float(words), float(words), float(words))
+        }
+        fn id(words: &mut impl Iterator<Item = u32>) -> Option<u32> {
+            Some(words.next().unwrap()).filter(|id| *id != u32::MAX)
+        }
+        let w = &mut words.iter().copied();
+        for p in &mut self.pixels {
+            p.color = vec3(w);
+            p.weight = float(w);
+            p.aovs.depth = float(w);
+            p.aovs.normal = vec3(w);
+            p.aovs.albedo = vec3(w);
+            p.aovs.emission = vec3(w);
+            p.aovs.direct = vec3(w);
+            p.aovs.indirect = vec3(w);
+            p.aovs.material_id = id(w).map(|id| id as u8);
+            p.aovs.object_id = id(w).map(|id| id as usize);
+            p.samples = w.next().unwrap();
+            p.mean = float(w);
+            p.m2 = float(w);
+        }
+        for c in &mut self.light_path_colors {
+            *c = vec3(w);
+        }
+        Ok(())
+    }
+
+    /// Records the AOVs of a sample taken for `pixel`.
+    pub fn add_aovs(&mut self, pixel: (usize, usize), aovs: &Aovs) {
+        let tile_width = self.x_max - self.x_min;
+        self.pixels[(pixel.1 - self.y_min) * tile_width + (pixel.0 - self.x_min)]
+            .aovs
+            .accumulate(aovs);
+    }
+}
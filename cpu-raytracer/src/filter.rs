@@ -0,0 +1,107 @@
+/// Pixel reconstruction filter. Filters are separable and evaluated on
+/// offsets measured in pixels from the pixel center.
+#[derive(Copy, Clone)]
+pub enum Filter {
+    Box { radius: f32 },
+    Tent { radius: f32 },
+    Gaussian { radius: f32, alpha: f32 },
+    Mitchell { radius: f32, b: f32, c: f32 },
+    Lanczos { radius: f32, tau: f32 },
+}
+
+impl Filter {
+    /// Creates the filter called `name` with its default parameters, using
+    /// `radius` instead of the default radius if given.
+    pub fn from_name(name: &str, radius: Option<f32>) -> Option<Filter> {
+        Some(match name {
+            "box" => Filter::Box {
+                radius: radius.unwrap_or(0.5),
+            },
+            "tent" => Filter::Tent {
+                radius: radius.unwrap_or(1.0),
+            },
+            "gaussian" => Filter::Gaussian {
+                radius: radius.unwrap_or(1.5),
+                alpha: 2.0,
+            },
+            "mitchell" => Filter::Mitchell {
+                radius: radius.unwrap_or(2.0),
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            "lanczos" => {
+                let radius = radius.unwrap_or(3.0);
+                Filter::Lanczos {
+                    radius,
+                    tau: radius,
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius, .. } => radius,
+        }
+    }
+
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match *self {
+            Filter::Box { radius } => {
+                if x <= radius {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Tent { radius } => (radius - x).max(0.0),
+            Filter::Gaussian { radius, alpha } => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                // The Mitchell-Netravali polynomial is defined over [-2, 2].
+                let x = 2.0 * x / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else if x < 2.0 {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Lanczos { radius, tau } => {
+                if x > radius {
+                    0.0
+                } else {
+                    sinc(x) * sinc(x / tau)
+                }
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
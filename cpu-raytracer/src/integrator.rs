@@ -0,0 +1,387 @@
+use crate::bdpt::Bidirectional;
+use crate::camera::Camera;
+use crate::debug::{AmbientOcclusion, DebugView};
+use crate::film::{Aovs, Film, SplatBuffer};
+use crate::lpe::{Event, PathRecord};
+use crate::material::Scatter;
+use crate::mlt;
+use crate::ray::Ray;
+use crate::render::Settings;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
+use crate::sppm;
+use crate::vec3::Vec3;
+use crate::whitted::Whitted;
+
+pub const BOUNCES: usize = 8;
+
+/// Light transport algorithm that estimates the radiance of every camera
+/// sample handed to it by the tile loop in `render`.
+pub trait Integrator: Sync {
+    /// Called once per render, before any sample is taken.
+    fn preprocess(&mut self, _scene: &Scene) {}
+
+    /// Radiance arriving along the camera `ray`, in linear RGB.
+    fn li(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        output: &mut SampleOutput,
+    ) -> Vec3;
+}
+
+/// Everything a camera sample produces besides its radiance.
+pub struct SampleOutput<'a> {
+    pub aovs: Aovs,
+    /// Present when light path expressions are evaluated.
+    pub path: Option<PathRecord>,
+    /// Light that lands on other pixels than the one sampled.
+    pub splats: &'a SplatBuffer,
+}
+
+pub enum Technique {
+    /// Creates the integrator for a render; samples go through the tiles.
+    Sampled(fn(&Camera, &Settings) -> Box<dyn Integrator>),
+    /// Renders the whole film by itself, for integrators that do not take
+    /// independent samples per pixel.
+    Image(fn(&Scene, &Camera, &Settings) -> Film),
+}
+
+pub struct Registration {
+    pub name: &'static str,
+    pub technique: Technique,
+}
+
+/// Every integrator selectable with `--integrator`. The first is the
+/// default.
+pub static INTEGRATORS: &[Registration] = &[
+    Registration {
+        name: "path",
+        technique: Technique::Sampled(|_, settings| {
+            Box::new(PathTracer {
+                spectral: settings.spectral,
+            })
+        }),
+    },
+    Registration {
+        name: "bdpt",
+        technique: Technique::Sampled(|camera, settings| {
+            Box::new(Bidirectional::new(*camera, settings.width, settings.height))
+        }),
+    },
+    Registration {
+        name: "mlt",
+        technique: Technique::Image(mlt::render),
+    },
+    Registration {
+        name: "sppm",
+        technique: Technique::Image(sppm::render),
+    },
+    Registration {
+        name: "whitted",
+        technique: Technique::Sampled(|_, _| Box::new(Whitted)),
+    },
+    Registration {
+        name: "ao",
+        technique: Technique::Sampled(|_, settings| {
+            Box::new(AmbientOcclusion {
+                distance: settings.ao_distance,
+            })
+        }),
+    },
+    Registration {
+        name: "normal",
+        technique: Technique::Sampled(|_, _| Box::new(DebugView::Normal)),
+    },
+    Registration {
+        name: "uv",
+        technique: Technique::Sampled(|_, _| Box::new(DebugView::Uv)),
+    },
+    Registration {
+        name: "depth",
+        technique: Technique::Sampled(|_, _| Box::new(DebugView::Depth)),
+    },
+    Registration {
+        name: "bounces",
+        technique: Technique::Sampled(|_, _| Box::new(DebugView::Bounces)),
+    },
+    Registration {
+        name: "cost",
+        technique: Technique::Sampled(|_, _| Box::new(DebugView::Cost)),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Registration> {
+    INTEGRATORS.iter().find(|r| r.name == name)
+}
+
+/// Unidirectional path tracing, `ray_color`, or `spectral_ray_color` with a
+/// hero wavelength per path.
+pub struct PathTracer {
+    pub spectral: bool,
+}
+
+impl Integrator for PathTracer {
+    fn li(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        output: &mut SampleOutput,
+    ) -> Vec3 {
+        let aovs = &mut output.aovs;
+        if !self.spectral {
+            return ray_color(ray, scene, sampler, aovs, output.path.as_mut());
+        }
+        let mut lambda = SampledWavelengths::sample(sampler.get_1d());
+        let radiance =
+            spectral_ray_color(ray, scene, &mut lambda, sampler, aovs, output.path.as_mut());
+        lambda.radiance_to_rgb(radiance)
+    }
+}
+
+pub fn sky_color(ray: Ray) -> Vec3 {
+    let t = (ray.direction.normalize().y + 1.0) * 0.5;
+    let white = Vec3::one();
+    let blue = Vec3::new(0.5, 0.7, 1.0);
+    white.lerp(blue, t)
+}
+
+/// Light arriving at the camera split by the number of bounces it took:
+/// emission seen directly, after one bounce and after more.
+pub const EMITTED: usize = 0;
+pub const DIRECT: usize = 1;
+pub const INDIRECT: usize = 2;
+
+pub fn contribution(bounce: usize) -> usize {
+    bounce.min(INDIRECT)
+}
+
+/// Records the first event along a camera path, either a surface hit or a
+/// collision inside a volume.
+pub fn record_first_hit(
+    aovs: &mut Aovs,
+    ray: Ray,
+    t: f32,
+    albedo: Vec3,
+    normal: Vec3,
+    material_id: Option<u8>,
+    object_id: usize,
+) {
+    aovs.depth = t * ray.direction.magnitude();
+    aovs.normal = normal;
+    aovs.albedo = albedo;
+    aovs.material_id = material_id;
+    aovs.object_id = Some(object_id);
+}
+
+/// Quantities carried along a path by `trace_path`: linear RGB for `Rgb`,
+/// or one value per wavelength for `SampledWavelengths`.
+trait Radiometry {
+    type Value: Copy;
+
+    fn splat(&self, v: f32) -> Self::Value;
+
+    /// Converts an RGB reflectance or radiance met along the path.
+    fn upsample(&self, rgb: Vec3) -> Self::Value;
+
+    fn to_rgb(&self, value: Self::Value) -> Vec3;
+
+    fn add(a: Self::Value, b: Self::Value) -> Self::Value;
+
+    fn mul(a: Self::Value, b: Self::Value) -> Self::Value;
+
+    /// Wavelength handed to materials, for dispersion.
+    fn wavelength(&self) -> Option<f32>;
+
+    /// Called after every scattering event at a surface.
+    fn scattered(&mut self, scatter: &Scatter);
+}
+
+struct Rgb;
+
+impl Radiometry for Rgb {
+    type Value = Vec3;
+
+    fn splat(&self, v: f32) -> Vec3 {
+        Vec3::new(v, v, v)
+    }
+
+    fn upsample(&self, rgb: Vec3) -> Vec3 {
+        rgb
+    }
+
+    fn to_rgb(&self, value: Vec3) -> Vec3 {
+        value
+    }
+
+    fn add(a: Vec3, b: Vec3) -> Vec3 {
+        a.add(b)
+    }
+
+    fn mul(a: Vec3, b: Vec3) -> Vec3 {
+        a.hadamard(b)
+    }
+
+    fn wavelength(&self) -> Option<f32> {
+        None
+    }
+
+    fn scattered(&mut self, _scatter: &Scatter) {}
+}
+
+impl Radiometry for SampledWavelengths {
+    type Value = SampledSpectrum;
+
+    fn splat(&self, v: f32) -> SampledSpectrum {
+        SampledSpectrum::splat(v)
+    }
+
+    fn upsample(&self, rgb: Vec3) -> SampledSpectrum {
+        SampledWavelengths::upsample(self, rgb)
+    }
+
+    fn to_rgb(&self, value: SampledSpectrum) -> Vec3 {
+        self.radiance_to_rgb(value)
+    }
+
+    fn add(a: SampledSpectrum, b: SampledSpectrum) -> SampledSpectrum {
+        a.add(b)
+    }
+
+    fn mul(a: SampledSpectrum, b: SampledSpectrum) -> SampledSpectrum {
+        a.mul(b)
+    }
+
+    fn wavelength(&self) -> Option<f32> {
+        Some(self.hero())
+    }
+
+    fn scattered(&mut self, scatter: &Scatter) {
+        if scatter.dispersive {
+            self.terminate_secondary();
+        }
+    }
+}
+
+/// Traces a path from `ray` and returns its radiance. The first event and
+/// the split of the radiance by bounce count are written to `aovs`; the
+/// first hit variables are left untouched for paths that escape. When
+/// `path` is given, the path's events and emissions are recorded in it.
+pub fn ray_color(
+    ray: Ray,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+    path: Option<&mut PathRecord>,
+) -> Vec3 {
+    trace_path(ray, scene, &mut Rgb, sampler, aovs, path)
+}
+
+/// Spectral counterpart of `ray_color`, tracing the wavelengths in `lambda`
+/// along a single path and upsampling RGB quantities as they are met.
+pub fn spectral_ray_color(
+    ray: Ray,
+    scene: &Scene,
+    lambda: &mut SampledWavelengths,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+    path: Option<&mut PathRecord>,
+) -> SampledSpectrum {
+    trace_path(ray, scene, lambda, sampler, aovs, path)
+}
+
+/// The bounce loop shared by `ray_color` and `spectral_ray_color`.
+fn trace_path<R: Radiometry>(
+    ray: Ray,
+    scene: &Scene,
+    radiometry: &mut R,
+    sampler: &mut dyn Sampler,
+    aovs: &mut Aovs,
+    mut path: Option<&mut PathRecord>,
+) -> R::Value {
+    let mut ray = ray;
+    let mut atten = radiometry.splat(1.0);
+    let mut color = [radiometry.splat(0.0); 3];
+    // Emissions are converted to RGB once the wavelength pdfs are final.
+    let mut emissions = Vec::new();
+    for bounce in 0..BOUNCES {
+        let c = contribution(bounce);
+        let hit = scene.intersect(ray);
+
+        let t_max = hit.map_or(f32::MAX, |h| h.t);
+        if let Some((t, index)) = scene.sample_volumes(ray, t_max, sampler) {
+            let volume = &scene.volumes[index];
+            if bounce == 0 {
+                let normal = ray.direction.normalize().scale(-1.0);
+                let object = scene.volume_object(index);
+                record_first_hit(aovs, ray, t, volume.albedo, normal, None, object);
+            }
+
+            // Collision estimator: the absorbed fraction contributes the
+            // medium's emission and the scattered fraction continues the path.
+            let point = ray.at(t);
+            let absorption = Vec3::one().sub(volume.albedo);
+            let emission = volume.emission(point).hadamard(absorption);
+            let emitted = R::mul(atten, radiometry.upsample(emission));
+            color[c] = R::add(color[c], emitted);
+            atten = R::mul(atten, radiometry.upsample(volume.albedo));
+            if let Some(path) = path.as_deref_mut() {
+                emissions.push((path.events.len(), Event::Light, emitted));
+                path.events.push(Event::Volume);
+            }
+            ray = Ray {
+                origin: point,
+                direction: sampler.unit(),
+            };
+            continue;
+        }
+
+        if let Some(hit) = hit {
+            let material = scene.materials[hit.material as usize];
+            if bounce == 0 {
+                let albedo = material.albedo();
+                let id = Some(hit.material);
+                record_first_hit(aovs, ray, hit.t, albedo, hit.normal, id, hit.object);
+            }
+
+            let emitted = R::mul(atten, radiometry.upsample(material.emission));
+            color[c] = R::add(color[c], emitted);
+            if let Some(path) = path.as_deref_mut() {
+                emissions.push((path.events.len(), Event::Light, emitted));
+            }
+            match material.scatter(ray, &hit, scene, radiometry.wavelength(), sampler) {
+                Some(scatter) => {
+                    if let Some(path) = path.as_deref_mut() {
+                        path.events.push(scatter.event);
+                    }
+                    radiometry.scattered(&scatter);
+                    ray = scatter.ray;
+                    atten = R::mul(atten, radiometry.upsample(scatter.attenuation));
+                }
+                None => break,
+            }
+        } else {
+            let emitted = R::mul(atten, radiometry.upsample(sky_color(ray)));
+            color[c] = R::add(color[c], emitted);
+            if let Some(path) = path.as_deref_mut() {
+                emissions.push((path.events.len(), Event::Background, emitted));
+            }
+            break;
+        }
+    }
+
+    // The split is converted with the final wavelength pdfs, the same ones
+    // the caller resolves the returned radiance with.
+    aovs.emission = radiometry.to_rgb(color[EMITTED]);
+    aovs.direct = radiometry.to_rgb(color[DIRECT]);
+    aovs.indirect = radiometry.to_rgb(color[INDIRECT]);
+    if let Some(path) = path {
+        for (length, event, emitted) in emissions {
+            path.emit_at(length, event, radiometry.to_rgb(emitted));
+        }
+    }
+    R::add(R::add(color[EMITTED], color[DIRECT]), color[INDIRECT])
+}
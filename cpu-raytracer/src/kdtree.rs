@@ -0,0 +1,124 @@
+//! Balanced kd-tree for finding the points within a radius of a position,
+//! used as the photon store of the photon mapping integrator.
+
+use crate::vec3::Vec3;
+
+struct Node<T> {
+    point: Vec3,
+    /// Axis the node splits its subtree along.
+    axis: u8,
+    value: T,
+}
+
+/// Points with attached values, stored as an implicit tree in a flat array:
+/// the middle element of every range is the root of that range's subtree,
+/// with the smaller points along its axis before it and the larger after.
+pub struct KdTree<T> {
+    nodes: Vec<Node<T>>,
+}
+
+fn component(v: Vec3, axis: u8) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+impl<T> KdTree<T> {
+    /// Builds the tree in O(n log n). The result only depends on the order
+    /// of `items` for points that compare equal along a split axis.
+    pub fn build(items: Vec<(Vec3, T)>) -> KdTree<T> {
+        let mut nodes: Vec<Node<T>> = items
+            .into_iter()
+            .map(|(point, value)| Node {
+                point,
+                axis: 0,
+                value,
+            })
+            .collect();
+        build(&mut nodes);
+        KdTree { nodes }
+    }
+
+    /// Calls `f` with every point within `radius` of `center` and its value.
+    pub fn for_each_within<F: FnMut(Vec3, &T)>(&self, center: Vec3, radius: f32, mut f: F) {
+        query(&self.nodes, center, radius, &mut f);
+    }
+}
+
+fn build<T>(nodes: &mut [Node<T>]) {
+    if nodes.len() <= 1 {
+        return;
+    }
+
+    // Split along the axis the points are spread out the most.
+    let (min, max) = nodes.iter().fold(
+        (Vec3::one().scale(f32::MAX), Vec3::one().scale(f32::MIN)),
+        |(min, max), n| (min.min(n.point), max.max(n.point)),
+    );
+    let extent = max.sub(min);
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+
+    let mid = nodes.len() / 2;
+    nodes.select_nth_unstable_by(mid, |a, b| {
+        component(a.point, axis).total_cmp(&component(b.point, axis))
+    });
+    nodes[mid].axis = axis;
+
+    let (left, right) = nodes.split_at_mut(mid);
+    build(left);
+    build(&mut right[1..]);
+}
+
+fn query<T, F: FnMut(Vec3, &T)>(nodes: &[Node<T>], center: Vec3, radius: f32, f: &mut F) {
+    if nodes.is_empty() {
+        return;
+    }
+    let mid = nodes.len() / 2;
+    let node = &nodes[mid];
+    if node.point.sub(center).square_magnitude() <= radius * radius {
+        f(node.point, &node.value);
+    }
+
+    let d = component(center, node.axis) - component(node.point, node.axis);
+    if d <= radius {
+        query(&nodes[..mid], center, radius, f);
+    }
+    if d >= -radius {
+        query(&nodes[mid + 1..], center, radius, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{Rng, RngXorShift};
+
+    #[test]
+    fn finds_the_same_points_as_a_linear_search() {
+        let mut rng = RngXorShift::new(9);
+        let mut point = || Vec3::new(rng.bi(), rng.bi(), rng.bi() * 0.1);
+        let points: Vec<Vec3> = (0..2000).map(|_| point()).collect();
+        let centers: Vec<Vec3> = (0..50).map(|_| point()).collect();
+        let tree = KdTree::build(points.iter().copied().zip(0..).collect());
+
+        for center in centers {
+            for radius in [0.0, 0.05, 0.2] {
+                let mut found = Vec::new();
+                tree.for_each_within(center, radius, |_, i| found.push(*i));
+                found.sort();
+                let expected: Vec<usize> = (0..points.len())
+                    .filter(|i| points[*i].sub(center).square_magnitude() <= radius * radius)
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+}
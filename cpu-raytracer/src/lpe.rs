@@ -0,0 +1,283 @@
+//! Light path expressions: regular expressions over the events of a path,
+//! used to route the light of matching paths to their own output buffer.
+//!
+//! Every path starts with `C` and ends at the event that emitted the light.
+//!
+//! | Symbol | Event                           |
+//! |--------|---------------------------------|
+//! | `C`    | camera                          |
+//! | `D`    | diffuse reflection              |
+//! | `G`    | glossy reflection               |
+//! | `S`    | specular reflection             |
+//! | `T`    | transmission                    |
+//! | `V`    | volume scattering               |
+//! | `L`    | emitting surface or volume      |
+//! | `B`    | background                      |
+//!
+//! `.` matches any event but `C`, `[DG]` any of the listed events and `[^S]`
+//! any event but `C` and the listed ones. Items can be followed by `*`, `+`
+//! or `?`. Whitespace is ignored, so "diffuse bounce then emitter" is
+//! `C D L` and specular caustics on diffuse surfaces are `C D S+ L`.
+
+use crate::vec3::Vec3;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Event {
+    Camera,
+    Diffuse,
+    Glossy,
+    Specular,
+    Transmission,
+    Volume,
+    Light,
+    Background,
+}
+
+impl Event {
+    const SYMBOLS: [(char, Event); 8] = [
+        ('C', Event::Camera),
+        ('D', Event::Diffuse),
+        ('G', Event::Glossy),
+        ('S', Event::Specular),
+        ('T', Event::Transmission),
+        ('V', Event::Volume),
+        ('L', Event::Light),
+        ('B', Event::Background),
+    ];
+
+    fn from_symbol(c: char) -> Option<Event> {
+        Event::SYMBOLS
+            .iter()
+            .find(|(s, _)| *s == c)
+            .map(|(_, e)| *e)
+    }
+
+    pub fn symbol(self) -> char {
+        Event::SYMBOLS.iter().find(|(_, e)| *e == self).unwrap().0
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Every event but the camera.
+const ANY: u8 = !(1 << Event::Camera as u8);
+
+struct Item {
+    /// Bit set of the events this item accepts.
+    events: u8,
+    optional: bool,
+    repeat: bool,
+}
+
+pub struct LightPathExpression {
+    pub name: String,
+    items: Vec<Item>,
+}
+
+impl LightPathExpression {
+    pub fn parse(name: &str, expression: &str) -> Result<LightPathExpression, String> {
+        let error = |msg: String| format!("light path expression '{}': {}", expression, msg);
+
+        let mut items: Vec<Item> = Vec::new();
+        let mut chars = expression.chars().filter(|c| !c.is_whitespace()).peekable();
+        while let Some(c) = chars.next() {
+            let events = match c {
+                '.' => ANY,
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut set = 0;
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some(c) => {
+                                set |= Event::from_symbol(c)
+                                    .ok_or_else(|| error(format!("unknown event '{}'", c)))?
+                                    .bit()
+                            }
+                            None => return Err(error("missing ']'".to_string())),
+                        }
+                    }
+                    if negated {
+                        ANY & !set
+                    } else {
+                        set
+                    }
+                }
+                '*' | '+' | '?' => {
+                    return Err(error(format!("'{}' does not follow an event", c)));
+                }
+                c => Event::from_symbol(c)
+                    .ok_or_else(|| error(format!("unknown event '{}'", c)))?
+                    .bit(),
+            };
+
+            let (optional, repeat) = match chars.peek() {
+                Some('*') => (true, true),
+                Some('+') => (false, true),
+                Some('?') => (true, false),
+                _ => (false, false),
+            };
+            if optional || repeat {
+                chars.next();
+            }
+            items.push(Item {
+                events,
+                optional,
+                repeat,
+            });
+        }
+
+        if items.is_empty() {
+            return Err(error("empty expression".to_string()));
+        }
+
+        Ok(LightPathExpression {
+            name: name.to_string(),
+            items,
+        })
+    }
+
+    /// Whether the whole event sequence `path` matches the expression.
+    pub fn matches(&self, path: &[Event]) -> bool {
+        let n = self.items.len();
+        // Set of items the matcher can be at, including past the end.
+        let mut states = vec![false; n + 1];
+        states[0] = true;
+        self.skip_optional(&mut states);
+
+        for event in path {
+            let mut next = vec![false; n + 1];
+            for (i, item) in self.items.iter().enumerate() {
+                if states[i] && item.events & event.bit() != 0 {
+                    next[i + 1] = true;
+                    if item.repeat {
+                        next[i] = true;
+                    }
+                }
+            }
+            self.skip_optional(&mut next);
+            if !next.iter().any(|s| *s) {
+                return false;
+            }
+            states = next;
+        }
+
+        states[n]
+    }
+
+    fn skip_optional(&self, states: &mut [bool]) {
+        for (i, item) in self.items.iter().enumerate() {
+            if states[i] && item.optional {
+                states[i + 1] = true;
+            }
+        }
+    }
+}
+
+/// Events along a single camera path and the light each emission event
+/// contributed, recorded by the integrator.
+pub struct PathRecord {
+    /// Scattering events so far, starting with the camera.
+    pub events: Vec<Event>,
+    /// Number of scattering events before the emission, the emitting event
+    /// and the radiance it contributed.
+    contributions: Vec<(usize, Event, Vec3)>,
+}
+
+impl PathRecord {
+    pub fn new() -> PathRecord {
+        PathRecord {
+            events: vec![Event::Camera],
+            contributions: Vec::new(),
+        }
+    }
+
+    /// Records light emitted by `event` after the first `length` events,
+    /// so integrators can add it once the radiance of the path is known.
+    pub fn emit_at(&mut self, length: usize, event: Event, radiance: Vec3) {
+        // Most surfaces do not emit; they cannot add to any buffer.
+        if radiance.square_magnitude() > 0.0 {
+            self.contributions.push((length, event, radiance));
+        }
+    }
+
+    /// The scattering events in the notation of light path expressions.
+    pub fn describe(&self) -> String {
+        self.events.iter().map(|e| e.symbol()).collect()
+    }
+
+    /// Sum of the light of all recorded paths matching `expression`.
+    pub fn evaluate(&self, expression: &LightPathExpression) -> Vec3 {
+        let mut path = Vec::with_capacity(self.events.len() + 1);
+        let mut sum = Vec3::zero();
+        for (length, emitter, radiance) in &self.contributions {
+            path.clear();
+            path.extend_from_slice(&self.events[..*length]);
+            path.push(*emitter);
+            if expression.matches(&path) {
+                sum = sum.add(*radiance);
+            }
+        }
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::*;
+
+    fn matches(expression: &str, path: &[Event]) -> bool {
+        LightPathExpression::parse("test", expression)
+            .unwrap()
+            .matches(path)
+    }
+
+    #[test]
+    fn expressions_match_whole_paths() {
+        assert!(matches("C D L", &[Camera, Diffuse, Light]));
+        assert!(!matches("C D L", &[Camera, Diffuse, Diffuse, Light]));
+        assert!(!matches("C D", &[Camera, Diffuse, Light]));
+
+        assert!(matches(
+            "C D S+ L",
+            &[Camera, Diffuse, Specular, Specular, Light]
+        ));
+        assert!(!matches("C D S+ L", &[Camera, Diffuse, Light]));
+        assert!(matches("C S* L", &[Camera, Light]));
+        assert!(matches("C D? L", &[Camera, Light]));
+
+        assert!(matches("C .* [LB]", &[Camera, Glossy, Volume, Background]));
+        assert!(!matches("C . L", &[Camera, Camera, Light]));
+        assert!(matches(
+            "C [^S]* L",
+            &[Camera, Diffuse, Transmission, Light]
+        ));
+        assert!(!matches("C [^S]* L", &[Camera, Diffuse, Specular, Light]));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        for expression in ["", "C X L", "C [DG L", "* L"] {
+            assert!(LightPathExpression::parse("test", expression).is_err());
+        }
+    }
+
+    #[test]
+    fn contributions_are_routed_by_their_prefix() {
+        let mut record = PathRecord::new();
+        record.emit_at(1, Light, Vec3::new(1.0, 0.0, 0.0));
+        record.events.push(Diffuse);
+        record.emit_at(2, Light, Vec3::new(0.0, 1.0, 0.0));
+        record.events.push(Specular);
+        record.emit_at(3, Background, Vec3::new(0.0, 0.0, 1.0));
+
+        let eval = |e: &str| record.evaluate(&LightPathExpression::parse("test", e).unwrap());
+        assert_eq!(eval("C L").x, 1.0);
+        assert_eq!(eval("C D L").y, 1.0);
+        assert_eq!(eval("C D S B").z, 1.0);
+        assert_eq!(eval("C .* [LB]").luminance(), Vec3::one().luminance());
+    }
+}
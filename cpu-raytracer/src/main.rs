@@ -1,287 +1,223 @@
+mod aabb;
+mod aov;
+mod bdpt;
 mod camera;
+mod debug;
+mod denoise;
+mod distributed;
+mod exr;
+mod film;
+mod filter;
+mod integrator;
+mod kdtree;
+mod lpe;
 mod material;
 mod math;
+mod mlt;
+mod options;
 mod plane;
+mod principled;
+mod progress;
 mod random;
 mod ray;
+mod render;
+mod sampler;
+mod sampling;
+mod scene;
+mod scheduler;
+mod spectrum;
 mod sphere;
+mod sppm;
+mod stats;
 mod vec3;
+mod volume;
+mod whitted;
 
+use aabb::Aabb;
 use camera::Camera;
-use material::Material;
-use math::clamp01;
-use plane::Plane;
-use random::RngXorShift;
-use ray::Ray;
-use sphere::Sphere;
+use options::{Options, StatsFormat};
+use scene::Scene;
 use vec3::Vec3;
+use volume::{Volume, VoxelGrid};
 
 use std::fs::File;
-
-const SAMPLES: usize = 256;
-const SAMPLE_SCALE: f32 = 1.0 / (SAMPLES as f32);
-
-const BOUNCES: usize = 8;
+use std::io::Write;
+use std::net::TcpListener;
 
 fn main() {
-    let width = 1920;
-    let height = 1080;
-    let mut image_data: Vec<u8> = vec![0 as u8; width * height * 3];
+    let options = Options::from_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if let Some(address) = &options.worker {
+        let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+            eprintln!("--worker: failed to listen on {}: {}", address, e);
+            std::process::exit(1);
+        });
+        eprintln!("Listening for coordinators on {}", address);
+        let e = distributed::serve(listener, options.settings.threads, build_scene);
+        eprintln!("Failed to accept a coordinator: {}", e);
+        std::process::exit(1);
+    }
 
-    let camera = Camera::look_at(
-        Vec3::new(0.0, 0.0, 0.5),
-        Vec3::new(0.0, -0.25, -1.0),
-        std::f32::consts::FRAC_PI_2,
-        16.0 / 9.0,
-    );
+    let (scene, camera) = build_scene(&options).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut settings = options.settings;
+    if options.progress {
+        settings.progress = Some(Box::new(progress::print_bar));
+    }
 
     let now = std::time::Instant::now();
 
-    const TILE_SIZE: usize = 64;
-
-    let tile_count_x = (width + TILE_SIZE - 1) / TILE_SIZE;
-    let tile_count_y = (height + TILE_SIZE - 1) / TILE_SIZE;
-
-    let materials = vec![
-        Material {
-            reflection: Vec3::new(1.0, 1.0, 1.0),
-            emission: Vec3::new(0.0, 0.0, 0.0),
-            scattering: 1.0,
-        },
-        Material {
-            reflection: Vec3::new(1.0, 1.0, 1.0),
-            emission: Vec3::new(0.0, 0.0, 0.0),
-            scattering: 0.0,
-        },
-        Material {
-            reflection: Vec3::new(0.3, 0.3, 1.0),
-            emission: Vec3::new(0.0, 0.0, 0.0),
-            scattering: 1.0,
-        },
-        Material {
-            reflection: Vec3::new(1.0, 0.3, 0.3),
-            emission: Vec3::new(0.0, 0.0, 0.0),
-            scattering: 1.0,
-        },
-        Material {
-            reflection: Vec3::new(1.0, 1.0, 1.0),
-            emission: Vec3::new(0.7, 0.7, 0.7),
-            scattering: 1.0,
-        },
-    ];
-
-    let spheres = vec![
-        (
-            Sphere {
-                center: Vec3::new(0.25, -0.3, -0.15),
-                radius: 0.2,
-            },
-            0,
-        ),
-        (
-            Sphere {
-                center: Vec3::new(-0.25, -0.3, -0.25),
-                radius: 0.2,
-            },
-            1,
-        ),
-        (
-            Sphere {
-                center: Vec3::new(0.0, 0.6, -0.25),
-                radius: 0.25,
-            },
-            4,
-        ),
-    ];
-
-    let planes = vec![
-        (
-            Plane {
-                normal: Vec3::new(0.0, 1.0, 0.0),
-                distance: -0.5,
-            },
-            0,
-        ),
-        (
-            Plane {
-                normal: Vec3::new(0.0, -1.0, 0.0),
-                distance: -0.5,
-            },
-            0,
-        ),
-        (
-            Plane {
-                normal: Vec3::new(0.0, 0.0, -1.0),
-                distance: -0.5,
-            },
-            0,
-        ),
-        (
-            Plane {
-                normal: Vec3::new(0.0, 0.0, 1.0),
-                distance: -0.5,
-            },
-            0,
-        ),
-        (
-            Plane {
-                normal: Vec3::new(-1.0, 0.0, 0.0),
-                distance: -0.5,
-            },
-            2,
-        ),
-        (
-            Plane {
-                normal: Vec3::new(1.0, 0.0, 0.0),
-                distance: -0.5,
-            },
-            3,
-        ),
-    ];
-
-    let state = (&materials, &spheres, &planes);
+    let film = if options.workers.is_empty() {
+        render::render(&scene, &camera, &settings)
+    } else {
+        let mut coordinator =
+            distributed::Coordinator::connect(&options.workers, &options.worker_args)
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+        distributed::render(&scene, &camera, &settings, &mut coordinator)
+    };
+    let film = match settings.crop {
+        Some(crop) if options.crop_image => film.crop(&crop),
+        _ => film,
+    };
+    let (width, height) = (film.width, film.height);
 
-    struct Tile {
-        y_min: usize,
-        y_max: usize,
-        x_min: usize,
-        x_max: usize,
-        data: [u8; TILE_SIZE * TILE_SIZE * 3],
+    println!("Took: {}ms", now.elapsed().as_millis());
+    match options.stats {
+        Some(StatsFormat::Summary) => println!("{}", film.stats.summary()),
+        Some(StatsFormat::Json) => println!("{}", film.stats.json()),
+        None => {}
     }
-
-    let mut tasks: Vec<Tile> = Vec::with_capacity(tile_count_x * tile_count_y);
-
-    for y in 0..tile_count_y {
-        let y_min = y * TILE_SIZE;
-        let y_max = std::cmp::min(y_min + TILE_SIZE, height);
-        for x in 0..tile_count_x {
-            let x_min = x * TILE_SIZE;
-            let x_max = std::cmp::min(x_min + TILE_SIZE, width);
-
-            tasks.push(Tile {
-                y_min,
-                y_max,
-                x_min,
-                x_max,
-                data: [0; TILE_SIZE * TILE_SIZE * 3],
-            });
+    let image = if options.denoise {
+        film::encode_rgb8(width, height, &denoise::denoise(&film))
+    } else {
+        film.to_rgb8()
+    };
+    let image = match (&options.crop_merge, settings.crop) {
+        (Some(path), Some(crop)) => {
+            let mut base = read_image_from_file(path, width, height)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+            film::paste_rgb8(&mut base, &image, width, height, &crop);
+            base
         }
-    }
-
-    let mut pool = scoped_threadpool::Pool::new(4);
-
-    pool.scoped(|scope| {
-        for t in &mut tasks {
-            scope.execute(move || {
-                let mut rng = RngXorShift::new(58727590);
-
-                for y in (t.y_min..t.y_max).rev() {
-                    for x in t.x_min..t.x_max {
-                        let mut color = Vec3::zero();
-                        for _ in 0..SAMPLES {
-                            let u = (x as f32 + rng.bi()) / (width as f32 - 1.0);
-                            let v = (y as f32 + rng.bi()) / (height as f32 - 1.0);
-                            let ray = camera.ray_from_uv(u, v);
-
-                            color = color.add(ray_color(ray, state, &mut rng).scale(SAMPLE_SCALE));
-                        }
-
-                        let i = ((TILE_SIZE - (y - t.y_min) - 1) * TILE_SIZE + (x - t.x_min)) * 3;
-                        t.data[i] = (clamp01(color.x.sqrt()) * 255.0) as u8;
-                        t.data[i + 1] = (clamp01(color.y.sqrt()) * 255.0) as u8;
-                        t.data[i + 2] = (clamp01(color.z.sqrt()) * 255.0) as u8;
-                    }
-                }
-            });
+        _ => image,
+    };
+    write_image_to_file("image.png", width, height, &image);
+
+    if options.aov_exr {
+        aov::write_exr("image.exr", &film, &options.aovs)
+            .unwrap_or_else(|e| panic!("Failed to write image.exr: {}", e));
+    } else {
+        for aov in &options.aovs {
+            let path = format!("{}.png", aov.name());
+            write_image_to_file(&path, width, height, &aov.visualize(&film));
         }
-    });
+    }
 
-    for t in tasks {
-        for y in (t.y_min..t.y_max).rev() {
-            for x in t.x_min..t.x_max {
-                let i = ((height - y - 1) * width + x) * 3;
-                let task_i = ((TILE_SIZE - (y - t.y_min) - 1) * TILE_SIZE + (x - t.x_min)) * 3;
-                image_data[i + 0] = t.data[task_i + 0];
-                image_data[i + 1] = t.data[task_i + 1];
-                image_data[i + 2] = t.data[task_i + 2];
-            }
+    if !options.aov_exr {
+        for (i, name) in film.light_path_names().iter().enumerate() {
+            let image = film::encode_rgb8(width, height, &film.resolve_light_path(i));
+            write_image_to_file(&format!("{}.png", name), width, height, &image);
         }
     }
 
-    println!("Took: {}ms", now.elapsed().as_millis());
-    write_image_to_file(width, height, &image_data);
-}
+    if let Some(path) = options.sample_heatmap {
+        write_image_to_file(&path, width, height, &film.heatmap_rgb8());
+    }
 
-fn sky_color(ray: Ray) -> Vec3 {
-    let t = (ray.direction.normalize().y + 1.0) * 0.5;
-    let white = Vec3::one();
-    let blue = Vec3::new(0.5, 0.7, 1.0);
-    white.lerp(blue, t)
+    if let Some(path) = options.tile_timings {
+        write_tile_timings(&path, &film)
+            .unwrap_or_else(|e| panic!("Failed to write {}: {}", path, e));
+    }
 }
 
-fn ray_color(
-    ray: Ray,
-    state: (&Vec<Material>, &Vec<(Sphere, u8)>, &Vec<(Plane, u8)>),
-    rng: &mut RngXorShift,
-) -> Vec3 {
-    let mut ray = ray;
-    let mut atten = Vec3::one();
-    let mut color = Vec3::zero();
-    for _ in 0..BOUNCES {
-        let mut normal_index: Option<(Vec3, u8)> = None;
-
-        const MIN_DISTANCE: f32 = 0.0001;
+/// Builds the scene and camera the options describe. Workers of a
+/// distributed render build theirs from the coordinator's options too.
+fn build_scene(options: &Options) -> Result<(Scene, Camera), String> {
+    let settings = &options.settings;
+    let camera = Camera::look_at(
+        Vec3::new(0.0, 0.0, 0.5),
+        Vec3::new(0.0, -0.25, -1.0),
+        std::f32::consts::FRAC_PI_2,
+        settings.width as f32 / settings.height as f32,
+    );
 
-        let mut min = std::f32::MAX;
-        for (s, i) in state.1 {
-            let t = s.intersect(ray);
-            if t > MIN_DISTANCE && t < min {
-                min = t;
+    let mut scene = options.scene.create();
 
-                let point = ray.at(t);
-                normal_index = Some((s.normal(point), *i));
-            }
-        }
+    if let Some(v) = &options.volume {
+        let grid = VoxelGrid::load(&v.path)
+            .map_err(|e| format!("Failed to load voxel grid {}: {}", v.path, e))?;
+        scene.volumes.push(Volume {
+            grid,
+            bounds: Aabb {
+                min: v.min,
+                max: v.max,
+            },
+            density_scale: v.density,
+            albedo: v.albedo,
+            emission_scale: v.emission,
+        });
+    }
+    Ok((scene, camera))
+}
 
-        for (p, i) in state.2 {
-            let t = p.intersect(ray);
-            if t > MIN_DISTANCE && t < min {
-                min = t;
-                normal_index = Some((p.normal, *i));
-            }
-        }
+/// One line per tile and pass, with the start relative to the pass and the
+/// time taken in milliseconds.
+fn write_tile_timings(path: &str, film: &film::Film) -> std::io::Result<()> {
+    let mut w = std::io::BufWriter::new(File::create(path)?);
+    writeln!(w, "pass,x_min,x_max,y_min,y_max,thread,start_ms,time_ms")?;
+    for t in &film.tile_timings {
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{:.3},{:.3}",
+            t.pass,
+            t.x_min,
+            t.x_max,
+            t.y_min,
+            t.y_max,
+            t.timing.thread,
+            t.timing.start.as_secs_f64() * 1000.0,
+            t.timing.duration.as_secs_f64() * 1000.0
+        )?;
+    }
+    Ok(())
+}
 
-        if let Some((normal, index)) = normal_index {
-            let point = ray.at(min);
-            let material = state.0[index as usize];
-            let direction = {
-                let diffuse = normal.add(rng.unit());
-                let mirror = ray
-                    .direction
-                    .sub(normal.scale(normal.dot(ray.direction) * 2.0));
-                mirror.lerp(diffuse, material.scattering)
-            };
-            ray = Ray {
-                origin: point,
-                direction,
-            };
-            color = color.add(atten.hadamard(material.emission));
-            atten = atten.scale(0.5).hadamard(material.reflection);
-        } else {
-            return atten.hadamard(sky_color(ray));
-        }
+/// Reads an 8-bit RGB PNG of the given size, top row first.
+fn read_image_from_file(path: &str, width: usize, height: usize) -> Result<Vec<u8>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let (info, mut reader) = png::Decoder::new(file)
+        .read_info()
+        .map_err(|e| e.to_string())?;
+    if info.color_type != png::ColorType::RGB || info.bit_depth != png::BitDepth::Eight {
+        return Err("expected an 8-bit RGB image".to_string());
+    }
+    if (info.width as usize, info.height as usize) != (width, height) {
+        return Err(format!(
+            "expected a {}x{} image, got {}x{}",
+            width, height, info.width, info.height
+        ));
     }
-    color
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| e.to_string())?;
+    Ok(data)
 }
 
-fn write_image_to_file(width: usize, height: usize, image_data: &Vec<u8>) {
-    let file = File::create("image.png").expect("Failed to create file image.png");
+fn write_image_to_file(path: &str, width: usize, height: usize, image_data: &[u8]) {
+    let file =
+        File::create(path).unwrap_or_else(|e| panic!("Failed to create file {}: {}", path, e));
 
-    let ref mut w = std::io::BufWriter::new(file);
+    let w = &mut std::io::BufWriter::new(file);
     let mut encoder = png::Encoder::new(w, width as u32, height as u32);
     encoder.set_color(png::ColorType::RGB);
     encoder.set_depth(png::BitDepth::Eight);
     let mut writer = encoder.write_header().unwrap();
 
-    writer.write_image_data(image_data.as_slice()).unwrap();
+    writer.write_image_data(image_data).unwrap();
 }
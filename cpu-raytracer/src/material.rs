@@ -1,8 +1,199 @@
+use crate::lpe::Event;
+use crate::principled::Principled;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::cosine_hemisphere;
+use crate::scene::{Hit, Scene};
 use crate::Vec3;
 
+/// Scattering model used when a path hits a surface with this material.
+#[derive(Copy, Clone)]
+pub enum Model {
+    /// Blend between a perfect mirror and a diffuse bounce by `scattering`.
+    Standard,
+    /// Random walk through the interior of closed geometry.
+    Subsurface(Subsurface),
+    /// Layered principled BSDF; `reflection` and `scattering` are unused.
+    Principled(Principled),
+}
+
 #[derive(Copy, Clone)]
 pub struct Material {
     pub reflection: Vec3,
     pub emission: Vec3,
     pub scattering: f32,
+    pub model: Model,
+}
+
+/// Outgoing ray and the throughput it carries after a surface interaction.
+pub struct Scatter {
+    pub ray: Ray,
+    pub attenuation: Vec3,
+    /// The outgoing direction depends on the wavelength being traced.
+    pub dispersive: bool,
+    /// Kind of interaction, for light path expressions.
+    pub event: Event,
+}
+
+impl Material {
+    /// Approximate hemispherical reflectance, for feature buffers.
+    pub fn albedo(&self) -> Vec3 {
+        match self.model {
+            Model::Standard => self.reflection,
+            Model::Subsurface(s) => s.albedo,
+            Model::Principled(p) => p.base_color,
+        }
+    }
+
+    /// Albedo of fully diffuse standard materials, whose lambertian BSDF is
+    /// the only one that can be evaluated for an arbitrary pair of
+    /// directions. Scattering through other materials has to be sampled.
+    pub fn lambertian(&self) -> Option<Vec3> {
+        match self.model {
+            Model::Standard if self.scattering == 1.0 => Some(self.reflection.scale(0.5)),
+            _ => None,
+        }
+    }
+
+    /// `wavelength` is the hero wavelength in nanometers when rendering in
+    /// spectral mode.
+    pub fn scatter(
+        &self,
+        ray: Ray,
+        hit: &Hit,
+        scene: &Scene,
+        wavelength: Option<f32>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Scatter> {
+        let point = ray.at(hit.t);
+        let normal = hit.normal;
+        match self.model {
+            Model::Standard => {
+                // Two-sided: scatter on the side the ray arrived from.
+                let normal = if normal.dot(ray.direction) > 0.0 {
+                    normal.scale(-1.0)
+                } else {
+                    normal
+                };
+                let diffuse = cosine_hemisphere(normal, sampler.get_2d()).direction;
+                let mirror = ray
+                    .direction
+                    .sub(normal.scale(normal.dot(ray.direction) * 2.0));
+                Some(Scatter {
+                    ray: Ray {
+                        origin: point,
+                        direction: mirror.lerp(diffuse, self.scattering),
+                    },
+                    attenuation: self.reflection.scale(0.5),
+                    dispersive: false,
+                    event: if self.scattering == 0.0 {
+                        Event::Specular
+                    } else if self.scattering == 1.0 {
+                        Event::Diffuse
+                    } else {
+                        Event::Glossy
+                    },
+                })
+            }
+            Model::Subsurface(s) => s.walk(ray, point, normal, scene, sampler),
+            Model::Principled(p) => p.scatter(ray, point, normal, wavelength, sampler),
+        }
+    }
+}
+
+/// Homogeneous subsurface medium described by its single-scattering albedo
+/// and mean free path per color channel.
+#[derive(Copy, Clone)]
+pub struct Subsurface {
+    pub albedo: Vec3,
+    pub mean_free_path: Vec3,
+}
+
+const MAX_WALK_STEPS: usize = 256;
+
+fn exp(v: Vec3) -> Vec3 {
+    Vec3::new(v.x.exp(), v.y.exp(), v.z.exp())
+}
+
+fn average(v: Vec3) -> f32 {
+    (v.x + v.y + v.z) / 3.0
+}
+
+impl Subsurface {
+    /// Enters the surface at `point`, walks through the medium and returns
+    /// the ray leaving the geometry, or `None` if the walk does not escape.
+    ///
+    /// Distances are sampled from one randomly chosen channel and weighted
+    /// by the average pdf over all channels (one-sample spectral MIS).
+    fn walk(
+        &self,
+        ray: Ray,
+        point: Vec3,
+        normal: Vec3,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Scatter> {
+        let sigma_t = Vec3::new(
+            1.0 / self.mean_free_path.x,
+            1.0 / self.mean_free_path.y,
+            1.0 / self.mean_free_path.z,
+        );
+        let sigma_s = sigma_t.hadamard(self.albedo);
+
+        // Orient the normal against the incoming ray so the walk always
+        // starts on the inside.
+        let inward = if normal.dot(ray.direction) < 0.0 {
+            normal.scale(-1.0)
+        } else {
+            normal
+        };
+
+        let mut throughput = Vec3::one();
+        let mut walk = Ray {
+            origin: point,
+            direction: cosine_hemisphere(inward, sampler.get_2d()).direction,
+        };
+
+        for _ in 0..MAX_WALK_STEPS {
+            let channel_sigma = match (sampler.get_1d() * 3.0) as usize {
+                0 => sigma_t.x,
+                1 => sigma_t.y,
+                _ => sigma_t.z,
+            };
+            let distance = -(1.0 - sampler.get_1d()).ln() / channel_sigma;
+
+            let hit = scene.intersect(walk)?;
+            if hit.t <= distance {
+                let transmittance = exp(sigma_t.scale(-hit.t));
+                throughput = throughput.hadamard(transmittance.scale(1.0 / average(transmittance)));
+
+                let outward = if hit.normal.dot(walk.direction) > 0.0 {
+                    hit.normal
+                } else {
+                    hit.normal.scale(-1.0)
+                };
+                return Some(Scatter {
+                    ray: Ray {
+                        origin: walk.at(hit.t),
+                        direction: cosine_hemisphere(outward, sampler.get_2d()).direction,
+                    },
+                    attenuation: throughput,
+                    dispersive: false,
+                    // The walk leaves the surface with a diffuse lobe.
+                    event: Event::Diffuse,
+                });
+            }
+
+            let transmittance = exp(sigma_t.scale(-distance));
+            let pdf = average(sigma_t.hadamard(transmittance));
+            throughput = throughput.hadamard(sigma_s.hadamard(transmittance).scale(1.0 / pdf));
+
+            walk = Ray {
+                origin: walk.at(distance),
+                direction: sampler.unit(),
+            };
+        }
+
+        None
+    }
 }
@@ -15,5 +15,5 @@ pub fn clamp01<T>(value: T) -> T
 where
     T: PartialOrd + From<u8>,
 {
-    clamp(value, T::from(0 as u8), T::from(1 as u8))
+    clamp(value, T::from(0), T::from(1))
 }
@@ -0,0 +1,359 @@
+//! Primary sample space Metropolis light transport after Kelemen et al.,
+//! "A Simple and Robust Mutation Strategy for the Metropolis Light Transport
+//! Algorithm" (2002), with the structure of pbrt's `MLTIntegrator`.
+//!
+//! Markov chains wander through the random numbers the path tracer
+//! consumes, so once a chain has found a path carrying a lot of light it
+//! keeps exploring similar paths with small mutations. A bootstrap phase of
+//! independent paths estimates the overall image brightness the chains are
+//! normalized with, and every state is splatted onto the pixel it lands on.
+
+use crate::camera::Camera;
+use crate::film::{Aovs, Film, SplatBuffer};
+use crate::integrator::ray_color;
+use crate::progress::Reporter;
+use crate::random::{Pcg32, Rng};
+use crate::render::{firefly_scale, Settings};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::stats;
+use crate::vec3::Vec3;
+
+/// Standard deviation of a small step mutation of a single coordinate.
+const SIGMA: f32 = 0.01;
+
+#[derive(Copy, Clone)]
+struct PrimarySample {
+    value: f32,
+    /// Iteration the value was last mutated in.
+    modified: u64,
+    /// Value and iteration before the current mutation, restored when the
+    /// proposal is rejected.
+    backup: f32,
+    backup_modified: u64,
+}
+
+/// Sampler handing out the coordinates of a point in primary sample space.
+///
+/// Every iteration proposes a mutation of the point, either a large step
+/// that draws every coordinate anew or a small step that perturbs them.
+/// Mutations are applied lazily when a coordinate is requested, catching up
+/// on the iterations it was not used in.
+pub struct MetropolisSampler {
+    rng: Pcg32,
+    large_step_probability: f32,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    dimension: usize,
+}
+
+impl MetropolisSampler {
+    /// The initial state depends only on `seed` and `stream`, so it can be
+    /// traced again after the bootstrap phase picked it.
+    pub fn new(seed: u32, stream: u64, large_step_probability: f32) -> Self {
+        MetropolisSampler {
+            rng: Pcg32::new(seed as u64, stream),
+            large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            dimension: 0,
+        }
+    }
+
+    /// Starts proposing the next state of the chain.
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.uni() < self.large_step_probability;
+        self.dimension = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Returns to the state before the last `start_iteration`.
+    pub fn reject(&mut self) {
+        for s in &mut self.samples {
+            if s.modified == self.iteration {
+                s.value = s.backup;
+                s.modified = s.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Standard normal value, with the Box-Muller transform.
+    fn normal(&mut self) -> f32 {
+        let r = (-2.0 * (1.0 - self.rng.uni()).ln()).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * self.rng.uni();
+        r * phi.cos()
+    }
+}
+
+impl Sampler for MetropolisSampler {
+    /// States are started with `start_iteration` instead; the pixel is
+    /// picked by the first two coordinates.
+    fn start_pixel_sample(&mut self, _x: usize, _y: usize, _index: usize) {}
+
+    fn get_1d(&mut self) -> f32 {
+        let i = self.dimension;
+        self.dimension += 1;
+
+        if i == self.samples.len() {
+            // No earlier state used this coordinate, so any uniform value
+            // is a valid mutation of it.
+            let value = self.rng.uni();
+            self.samples.push(PrimarySample {
+                value,
+                modified: self.iteration,
+                backup: value,
+                backup_modified: self.iteration,
+            });
+            return value;
+        }
+
+        let mut s = self.samples[i];
+        if s.modified < self.last_large_step {
+            s.value = self.rng.uni();
+            s.modified = self.last_large_step;
+        }
+        s.backup = s.value;
+        s.backup_modified = s.modified;
+
+        if self.large_step {
+            s.value = self.rng.uni();
+        } else {
+            let steps = (self.iteration - s.modified) as f32;
+            s.value += self.normal() * SIGMA * steps.sqrt();
+            s.value -= s.value.floor();
+            // Rounding can land exactly on one.
+            if s.value >= 1.0 {
+                s.value = 0.0;
+            }
+        }
+        s.modified = self.iteration;
+
+        self.samples[i] = s;
+        s.value
+    }
+}
+
+/// Scalar the chains sample proportionally to.
+fn importance(color: Vec3) -> f32 {
+    let y = color.luminance();
+    if y.is_finite() && y > 0.0 {
+        y
+    } else {
+        0.0
+    }
+}
+
+/// Film position and radiance of the path the sampler's current state maps
+/// to, traced with the unidirectional path tracer.
+fn trace(scene: &Scene, camera: &Camera, settings: &Settings, sampler: &mut dyn Sampler) -> Sample {
+    let (u, v) = sampler.get_2d();
+    let ray = camera.ray_from_uv(u, v);
+    let color = ray_color(ray, scene, sampler, &mut Aovs::none(), None);
+    stats::count(|s| s.primary_rays += 1);
+    // A dropped sample has no importance, so the chain never moves to it.
+    let color = if color.is_finite() {
+        color.scale(firefly_scale(color, settings.max_luminance))
+    } else {
+        stats::count(|s| s.nonfinite_samples += 1);
+        Vec3::zero()
+    };
+    Sample {
+        x: u * settings.width as f32,
+        y: v * settings.height as f32,
+        color,
+        importance: importance(color),
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Sample {
+    x: f32,
+    y: f32,
+    color: Vec3,
+    importance: f32,
+}
+
+/// Renders `scene` with `settings.samples` mutations per pixel on average.
+/// The film only holds splats and the result does not depend on the thread
+/// count.
+pub fn render(scene: &Scene, camera: &Camera, settings: &Settings) -> Film {
+    let (width, height) = (settings.width, settings.height);
+    let mut film = Film::new(width, height, settings.filter, Vec::new());
+    let large_step = settings.mlt_large_step;
+    let mut pool = scoped_threadpool::Pool::new(settings.threads);
+    let mutations = (settings.samples * width * height) as u64;
+    let progress = Reporter::new(settings);
+    progress.add_work(settings.mlt_bootstrap as u64 + mutations);
+
+    // Bootstrap path `i` is the initial state of a sampler on stream `i`.
+    let mut weights = vec![0.0; settings.mlt_bootstrap];
+    let chunk_size = weights.len().div_ceil(settings.threads as usize);
+    pool.scoped(|scope| {
+        for (c, chunk) in weights.chunks_mut(chunk_size).enumerate() {
+            let progress = &progress;
+            scope.execute(move || {
+                for (i, w) in chunk.iter_mut().enumerate() {
+                    let stream = (c * chunk_size + i) as u64;
+                    let mut sampler = MetropolisSampler::new(settings.seed, stream, large_step);
+                    let start = stats::snapshot();
+                    *w = trace(scene, camera, settings, &mut sampler).importance;
+                    progress.advance(1, &stats::snapshot().since(&start));
+                }
+            });
+        }
+    });
+
+    let mut cdf = Vec::with_capacity(weights.len());
+    let mut total = 0.0f64;
+    for w in &weights {
+        total += *w as f64;
+        cdf.push(total);
+    }
+    film.count_samples(mutations);
+    if total == 0.0 {
+        film.stats = progress.finish();
+        return film;
+    }
+    // Average importance of an image sample.
+    let brightness = (total / weights.len() as f64) as f32;
+
+    let chains = settings.mlt_chains as u64;
+    let splats = film.splats();
+    pool.scoped(|scope| {
+        for chain in 0..chains {
+            let (cdf, progress) = (&cdf, &progress);
+            scope.execute(move || {
+                // Streams after the bootstrap ones drive the chains.
+                let stream = settings.mlt_bootstrap as u64 + chain;
+                let mut rng = Pcg32::new(settings.seed as u64, stream);
+                let u = rng.uni() as f64 * total;
+                let start = cdf.partition_point(|c| *c <= u).min(cdf.len() - 1);
+
+                let mut sampler = MetropolisSampler::new(settings.seed, start as u64, large_step);
+                let start = stats::snapshot();
+                let current = trace(scene, camera, settings, &mut sampler);
+                // Chains starting from the same bootstrap path must still
+                // mutate differently.
+                sampler.rng = Pcg32::new(settings.seed as u64, stream);
+
+                let count = mutations * (chain + 1) / chains - mutations * chain / chains;
+                run_chain(
+                    scene,
+                    camera,
+                    settings,
+                    &mut sampler,
+                    &mut rng,
+                    current,
+                    count,
+                    brightness,
+                    splats,
+                );
+                progress.advance(count, &stats::snapshot().since(&start));
+            });
+        }
+    });
+
+    film.stats = progress.finish();
+    film
+}
+
+/// Runs `count` mutations from the `current` state, splatting the expected
+/// contribution of both the current and the proposed state every time.
+#[allow(clippy::too_many_arguments)]
+fn run_chain(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &Settings,
+    sampler: &mut MetropolisSampler,
+    rng: &mut Pcg32,
+    mut current: Sample,
+    count: u64,
+    brightness: f32,
+    splats: &SplatBuffer,
+) {
+    for _ in 0..count {
+        sampler.start_iteration();
+        let proposal = trace(scene, camera, settings, sampler);
+        let accept = (proposal.importance / current.importance).min(1.0);
+
+        if accept > 0.0 {
+            let weight = accept * brightness / proposal.importance;
+            splats.add(proposal.x, proposal.y, proposal.color.scale(weight));
+        }
+        if accept < 1.0 {
+            let weight = (1.0 - accept) * brightness / current.importance;
+            splats.add(current.x, current.y, current.color.scale(weight));
+        }
+
+        if rng.uni() < accept {
+            current = proposal;
+            sampler.accept();
+        } else {
+            sampler.reject();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_mutations_are_undone() {
+        let mut sampler = MetropolisSampler::new(7, 0, 0.5);
+        let initial: Vec<f32> = (0..4).map(|_| sampler.get_1d()).collect();
+        for _ in 0..8 {
+            sampler.start_iteration();
+            let proposal: Vec<f32> = (0..6).map(|_| sampler.get_1d()).collect();
+            assert_ne!(proposal[..4], initial[..]);
+            sampler.reject();
+        }
+        assert_eq!(sampler.iteration, 0);
+        for (s, v) in sampler.samples.iter().zip(&initial) {
+            assert_eq!(s.value, *v);
+        }
+    }
+
+    #[test]
+    fn render_is_independent_of_thread_count() {
+        let render_with_threads = |threads| {
+            let settings = Settings {
+                width: 24,
+                height: 16,
+                samples: 2,
+                threads,
+                mlt_bootstrap: 500,
+                mlt_chains: 8,
+                ..Settings::default()
+            };
+            let camera = Camera::look_at(
+                Vec3::new(0.0, 0.0, 0.5),
+                Vec3::new(0.0, -0.25, -1.0),
+                std::f32::consts::FRAC_PI_2,
+                settings.width as f32 / settings.height as f32,
+            );
+            render(&Scene::cornell_box(), &camera, &settings).resolve()
+        };
+
+        let a = render_with_threads(1);
+        let b = render_with_threads(3);
+        assert!(a.iter().any(|c| c.luminance() > 0.0));
+        for (a, b) in a.iter().zip(&b) {
+            assert_eq!(
+                [a.x.to_bits(), a.y.to_bits(), a.z.to_bits()],
+                [b.x.to_bits(), b.y.to_bits(), b.z.to_bits()]
+            );
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::aov::Aov;
+use crate::film::Crop;
+use crate::filter::Filter;
+use crate::integrator::{self, Technique};
+use crate::lpe::LightPathExpression;
+use crate::random::RngKind;
+use crate::render::Settings;
+use crate::sampler::SamplerKind;
+use crate::scene::SceneKind;
+use crate::scheduler::TileOrder;
+use crate::vec3::Vec3;
+
+use std::convert::TryInto;
+use std::io::IsTerminal;
+
+pub struct VolumeOptions {
+    pub path: String,
+    pub min: Vec3,
+    pub max: Vec3,
+    pub density: f32,
+    pub albedo: Vec3,
+    pub emission: f32,
+}
+
+pub enum StatsFormat {
+    Summary,
+    Json,
+}
+
+pub struct Options {
+    pub scene: SceneKind,
+    pub volume: Option<VolumeOptions>,
+    /// Where to write a false color image of the per-pixel sample counts.
+    pub sample_heatmap: Option<String>,
+    /// Where to write how long every tile took, as CSV.
+    pub tile_timings: Option<String>,
+    /// Write only the crop window instead of the full frame.
+    pub crop_image: bool,
+    /// Full frame image to paste the crop window into, instead of leaving
+    /// the rest of the frame black.
+    pub crop_merge: Option<String>,
+    /// Print the render statistics after rendering.
+    pub stats: Option<StatsFormat>,
+    /// Show a progress bar on stderr. On by default when stderr is a
+    /// terminal.
+    pub progress: bool,
+    /// Run the feature guided denoiser on the final image.
+    pub denoise: bool,
+    pub aovs: Vec<Aov>,
+    /// Write the AOVs as layers of `image.exr` instead of one PNG each.
+    pub aov_exr: bool,
+    /// Address to listen on for coordinators as a worker, instead of
+    /// rendering.
+    pub worker: Option<String>,
+    /// Addresses of the workers to distribute the tiles to.
+    pub workers: Vec<String>,
+    /// The arguments the workers get, which are all but the ones about the
+    /// workers and the thread count.
+    pub worker_args: Vec<String>,
+    pub settings: Settings,
+}
+
+fn parse_usize(flag: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(format!(
+            "{}: expected a positive integer, got '{}'",
+            flag, value
+        )),
+    }
+}
+
+fn parse_f32(flag: &str, value: &str) -> Result<f32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{}: expected a number, got '{}'", flag, value))
+}
+
+fn parse_rect<T: std::str::FromStr>(flag: &str, value: &str) -> Result<[T; 4], String> {
+    let error = || format!("{}: expected x0,y0,x1,y1, got '{}'", flag, value);
+    let parts = value
+        .split(',')
+        .map(|p| p.trim().parse().map_err(|_| error()))
+        .collect::<Result<Vec<T>, String>>()?;
+    parts.try_into().map_err(|_| error())
+}
+
+fn parse_vec3(flag: &str, value: &str) -> Result<Vec3, String> {
+    let parts = value
+        .split(',')
+        .map(|p| parse_f32(flag, p.trim()))
+        .collect::<Result<Vec<f32>, String>>()?;
+    match parts.as_slice() {
+        [x, y, z] => Ok(Vec3::new(*x, *y, *z)),
+        _ => Err(format!("{}: expected x,y,z, got '{}'", flag, value)),
+    }
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse<I>(args: I) -> Result<Options, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut volume_path = None;
+        let mut volume = VolumeOptions {
+            path: String::new(),
+            min: Vec3::new(-0.2, -0.5, -0.5),
+            max: Vec3::new(0.2, 0.1, -0.1),
+            density: 20.0,
+            albedo: Vec3::new(0.8, 0.8, 0.8),
+            emission: 1.0,
+        };
+
+        let mut scene_name = String::from("cornell");
+        let mut settings = Settings::default();
+        let mut filter_name = String::from("box");
+        let mut filter_radius = None;
+        let mut sampler_name = String::from("independent");
+        let mut rng_name = String::from("xorshift");
+        let mut integrator_name = String::from("path");
+        let mut tile_order_name = String::from("scanline");
+        let mut sample_heatmap = None;
+        let mut tile_timings = None;
+        let mut stats = None;
+        let mut crop_pixels = None;
+        let mut crop_normalized = None;
+        let mut crop_output = String::from("full");
+        let mut crop_merge = None;
+        let mut progress = std::io::stderr().is_terminal();
+        let mut denoise = false;
+        let mut aov_names = String::new();
+        let mut aov_format = String::from("png");
+
+        let args: Vec<String> = args.into_iter().collect();
+        let worker_args = worker_args(&args);
+        let mut worker = None;
+        let mut workers = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("{}: missing value", flag))
+            };
+            match flag.as_str() {
+                "--scene" => scene_name = value()?,
+                "--volume" => volume_path = Some(value()?),
+                "--volume-min" => volume.min = parse_vec3(&flag, &value()?)?,
+                "--volume-max" => volume.max = parse_vec3(&flag, &value()?)?,
+                "--volume-density" => volume.density = parse_f32(&flag, &value()?)?,
+                "--volume-albedo" => volume.albedo = parse_vec3(&flag, &value()?)?,
+                "--volume-emission" => volume.emission = parse_f32(&flag, &value()?)?,
+                "--width" => settings.width = parse_usize(&flag, &value()?)?,
+                "--height" => settings.height = parse_usize(&flag, &value()?)?,
+                "--threads" => settings.threads = parse_usize(&flag, &value()?)? as u32,
+                "--tile-size" => settings.tile_size = parse_usize(&flag, &value()?)?,
+                "--tile-order" => tile_order_name = value()?,
+                "--tile-timings" => tile_timings = Some(value()?),
+                "--seed" => {
+                    let seed = value()?;
+                    settings.seed = seed
+                        .parse()
+                        .map_err(|_| format!("--seed: expected an integer, got '{}'", seed))?;
+                }
+                "--spectral" => settings.spectral = true,
+                "--filter" => filter_name = value()?,
+                "--filter-radius" => filter_radius = Some(parse_f32(&flag, &value()?)?),
+                "--samples" => settings.samples = parse_usize(&flag, &value()?)?,
+                "--sampler" => sampler_name = value()?,
+                "--rng" => rng_name = value()?,
+                "--integrator" => integrator_name = value()?,
+                "--adaptive-passes" => settings.adaptive_passes = parse_usize(&flag, &value()?)?,
+                "--adaptive-threshold" => {
+                    settings.adaptive_threshold = parse_f32(&flag, &value()?)?
+                }
+                "--mlt-bootstrap" => settings.mlt_bootstrap = parse_usize(&flag, &value()?)?,
+                "--mlt-chains" => settings.mlt_chains = parse_usize(&flag, &value()?)?,
+                "--mlt-large-step" => {
+                    settings.mlt_large_step = parse_f32(&flag, &value()?)?;
+                    if !(0.0..=1.0).contains(&settings.mlt_large_step) {
+                        return Err(format!("{}: expected a probability", flag));
+                    }
+                }
+                "--sppm-photons" => settings.sppm_photons = parse_usize(&flag, &value()?)?,
+                "--sppm-radius" => settings.sppm_radius = parse_f32(&flag, &value()?)?,
+                "--ao-distance" => settings.ao_distance = parse_f32(&flag, &value()?)?,
+                "--crop" => crop_pixels = Some(parse_rect::<usize>(&flag, &value()?)?),
+                "--crop-normalized" => crop_normalized = Some(parse_rect::<f32>(&flag, &value()?)?),
+                "--crop-output" => crop_output = value()?,
+                "--crop-merge" => crop_merge = Some(value()?),
+                "--max-luminance" => {
+                    settings.max_luminance = parse_f32(&flag, &value()?)?;
+                    if settings.max_luminance <= 0.0 {
+                        return Err(format!("{}: expected a positive number", flag));
+                    }
+                }
+                "--log-nonfinite" => settings.log_nonfinite = true,
+                "--sample-heatmap" => sample_heatmap = Some(value()?),
+                "--stats" => {
+                    stats = Some(match value()?.as_str() {
+                        "summary" => StatsFormat::Summary,
+                        "json" => StatsFormat::Json,
+                        other => return Err(format!("--stats: unknown format '{}'", other)),
+                    })
+                }
+                "--progress" => progress = true,
+                "--no-progress" => progress = false,
+                "--worker" => worker = Some(value()?),
+                "--workers" => {
+                    workers = value()?
+                        .split(',')
+                        .map(|a| a.trim().to_string())
+                        .filter(|a| !a.is_empty())
+                        .collect();
+                }
+                "--denoise" => denoise = true,
+                "--aovs" => aov_names = value()?,
+                "--aov-format" => aov_format = value()?,
+                "--lpe" => {
+                    let value = value()?;
+                    let (name, expression) = value
+                        .split_once('=')
+                        .filter(|(name, _)| !name.is_empty())
+                        .ok_or_else(|| {
+                            format!("{}: expected name=expression, got '{}'", flag, value)
+                        })?;
+                    settings
+                        .light_paths
+                        .push(LightPathExpression::parse(name, expression)?);
+                }
+                _ => return Err(format!("Unknown argument '{}'", flag)),
+            }
+        }
+
+        let scene = SceneKind::from_name(&scene_name)
+            .ok_or_else(|| format!("--scene: unknown scene '{}'", scene_name))?;
+
+        settings.filter = Filter::from_name(&filter_name, filter_radius)
+            .ok_or_else(|| format!("--filter: unknown filter '{}'", filter_name))?;
+
+        settings.sampler = SamplerKind::from_name(&sampler_name)
+            .ok_or_else(|| format!("--sampler: unknown sampler '{}'", sampler_name))?;
+
+        settings.rng = RngKind::from_name(&rng_name)
+            .ok_or_else(|| format!("--rng: unknown generator '{}'", rng_name))?;
+
+        settings.tile_order = TileOrder::from_name(&tile_order_name)
+            .ok_or_else(|| format!("--tile-order: unknown order '{}'", tile_order_name))?;
+
+        settings.integrator = integrator::find(&integrator_name)
+            .ok_or_else(|| format!("--integrator: unknown integrator '{}'", integrator_name))?;
+        if integrator_name != "path" {
+            if settings.spectral {
+                return Err("--spectral is only supported by the path integrator".to_string());
+            }
+            if !settings.light_paths.is_empty() {
+                return Err("--lpe is only supported by the path integrator".to_string());
+            }
+        }
+        let (width, height) = (settings.width, settings.height);
+        let crop_rect = match (crop_pixels, crop_normalized) {
+            (Some(_), Some(_)) => {
+                return Err("--crop and --crop-normalized are exclusive".to_string());
+            }
+            (Some(rect), None) => Some(rect),
+            (None, Some([u0, v0, u1, v1])) => {
+                if [u0, v0, u1, v1].iter().any(|v| !(0.0..=1.0).contains(v)) {
+                    return Err("--crop-normalized: expected values within 0..1".to_string());
+                }
+                // Round outwards, so partially covered pixels are included.
+                let x = |u: f32| (u * width as f32).floor() as usize;
+                let y = |v: f32| (v * height as f32).floor() as usize;
+                let x_end = |u: f32| ((u * width as f32).ceil() as usize).min(width);
+                let y_end = |v: f32| ((v * height as f32).ceil() as usize).min(height);
+                Some([x(u0), y(v0), x_end(u1), y_end(v1)])
+            }
+            (None, None) => None,
+        };
+        if let Some([x0, y0, x1, y1]) = crop_rect {
+            if x0 >= x1 || y0 >= y1 || x1 > width || y1 > height {
+                return Err(format!(
+                    "--crop: the window must be non-empty and inside the {}x{} image",
+                    width, height
+                ));
+            }
+            settings.crop = Some(Crop::from_image_rect(x0, y0, x1, y1, height));
+        }
+        let crop_image = match crop_output.as_str() {
+            "full" => false,
+            "cropped" => true,
+            _ => return Err(format!("--crop-output: unknown output '{}'", crop_output)),
+        };
+        if settings.crop.is_none() && (crop_image || crop_merge.is_some()) {
+            return Err("--crop-output and --crop-merge need a crop window".to_string());
+        }
+        if crop_image && crop_merge.is_some() {
+            return Err("--crop-merge needs the full frame output".to_string());
+        }
+
+        let sampled = matches!(settings.integrator.technique, Technique::Sampled(_));
+        if !sampled && settings.adaptive_passes > 0 {
+            return Err(format!(
+                "--adaptive-passes is not supported by the {} integrator",
+                integrator_name
+            ));
+        }
+        if !sampled && settings.crop.is_some() {
+            return Err(format!(
+                "--crop is not supported by the {} integrator",
+                integrator_name
+            ));
+        }
+        if !sampled && !workers.is_empty() {
+            return Err(format!(
+                "--workers is not supported by the {} integrator",
+                integrator_name
+            ));
+        }
+        if worker.is_some() && !workers.is_empty() {
+            return Err("--worker and --workers are exclusive".to_string());
+        }
+
+        let aovs = if aov_names == "all" {
+            Aov::ALL.to_vec()
+        } else {
+            aov_names
+                .split(',')
+                .filter(|n| !n.is_empty())
+                .map(|n| Aov::from_name(n).ok_or_else(|| format!("--aovs: unknown AOV '{}'", n)))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let aov_exr = match aov_format.as_str() {
+            "png" => false,
+            "exr" => true,
+            _ => return Err(format!("--aov-format: unknown format '{}'", aov_format)),
+        };
+
+        Ok(Options {
+            scene,
+            volume: volume_path.map(|path| VolumeOptions { path, ..volume }),
+            sample_heatmap,
+            tile_timings,
+            crop_image,
+            crop_merge,
+            stats,
+            progress,
+            denoise,
+            aovs,
+            aov_exr,
+            worker,
+            workers,
+            worker_args,
+            settings,
+        })
+    }
+}
+
+/// `args` without the flags that only concern the coordinator and their
+/// values.
+fn worker_args(args: &[String]) -> Vec<String> {
+    let mut worker_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--worker" | "--workers" | "--threads" => {
+                args.next();
+            }
+            _ => worker_args.push(arg.clone()),
+        }
+    }
+    worker_args
+}
@@ -1,4 +1,5 @@
 use crate::ray::Ray;
+use crate::sampling::Frame;
 use crate::vec3::Vec3;
 
 pub struct Plane {
@@ -11,10 +12,17 @@ impl Plane {
         const TOLERENCE: f32 = 0.0001;
         let denom = self.normal.dot(ray.direction);
 
-        if denom < -TOLERENCE || denom > TOLERENCE {
+        if !(-TOLERENCE..=TOLERENCE).contains(&denom) {
             (self.distance - self.normal.dot(ray.origin)) / denom
         } else {
             -1.0
         }
     }
+
+    /// Coordinates of `point` along two fixed axes of the plane, in world
+    /// units.
+    pub fn uv(&self, point: Vec3) -> (f32, f32) {
+        let frame = Frame::from_normal(self.normal);
+        (point.dot(frame.tangent), point.dot(frame.bitangent))
+    }
 }
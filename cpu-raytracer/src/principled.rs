@@ -0,0 +1,601 @@
+use crate::lpe::Event;
+use crate::material::Scatter;
+use crate::math::clamp;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sampling::{cosine_hemisphere, cosine_hemisphere_pdf, Frame};
+use crate::vec3::Vec3;
+
+use std::f32::consts::PI;
+
+/// Artist-facing layered material following the parameterization of the
+/// Disney principled BSDF, so values exported from DCC tools can be used as
+/// is. All scalar parameters are in [0, 1] except `ior`.
+#[derive(Copy, Clone)]
+pub struct Principled {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub specular: f32,
+    pub specular_tint: f32,
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    pub transmission: f32,
+    pub ior: f32,
+    /// Abbe number controlling dispersion in spectral mode; 0 disables it.
+    pub abbe: f32,
+}
+
+impl Default for Principled {
+    fn default() -> Principled {
+        Principled {
+            base_color: Vec3::new(0.8, 0.8, 0.8),
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.45,
+            abbe: 0.0,
+        }
+    }
+}
+
+fn schlick_weight(cos_theta: f32) -> f32 {
+    let m = clamp(1.0 - cos_theta, 0.0, 1.0);
+    let m2 = m * m;
+    m2 * m2 * m
+}
+
+fn schlick(f0: Vec3, cos_theta: f32) -> Vec3 {
+    f0.lerp(Vec3::one(), schlick_weight(cos_theta))
+}
+
+/// Unpolarized Fresnel reflectance of a dielectric interface, where `eta`
+/// is the ratio of the indices of refraction on the incident and
+/// transmitted side.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (rs * rs + rp * rp) * 0.5
+}
+
+fn reflect(v: Vec3, n: Vec3) -> Vec3 {
+    v.sub(n.scale(n.dot(v) * 2.0))
+}
+
+fn refract(v: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = -n.dot(v);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(v.scale(eta).add(n.scale(eta * cos_i - cos_t)))
+}
+
+/// Smith masking term for the GGX distribution.
+fn smith_g1(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let c2 = cos_theta * cos_theta;
+    2.0 * cos_theta / (cos_theta + (a2 + (1.0 - a2) * c2).sqrt())
+}
+
+/// Microfacet reflections at the minimum roughness are treated as specular.
+fn reflection_event(alpha: f32) -> Event {
+    if alpha <= 1e-3 {
+        Event::Specular
+    } else {
+        Event::Glossy
+    }
+}
+
+/// GGX distribution of microfacet normals at `cos_h` to the normal.
+fn ggx_d(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = cos_h * cos_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+/// Berry's distribution (GTR1) used by the Disney clearcoat lobe.
+fn gtr1_d(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+}
+
+/// Microfacet normal at `cos_theta` to `n` and azimuth `2 pi u`.
+fn microfacet_normal(n: Vec3, cos_theta: f32, u: f32) -> Vec3 {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u;
+    Frame::from_normal(n).local_to_world(Vec3::new(
+        sin_theta * phi.cos(),
+        sin_theta * phi.sin(),
+        cos_theta,
+    ))
+}
+
+/// Samples a GGX microfacet normal around `n` with roughness `alpha`.
+fn sample_ggx(n: Vec3, alpha: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.get_2d();
+    let tan2 = alpha * alpha * u1 / (1.0 - u1).max(1e-6);
+    microfacet_normal(n, 1.0 / (1.0 + tan2).sqrt(), u2)
+}
+
+/// Samples a GTR1 microfacet normal around `n` with roughness `alpha`.
+fn sample_gtr1(n: Vec3, alpha: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.get_2d();
+    let a2 = alpha * alpha;
+    let cos2 = (1.0 - a2.powf(1.0 - u1)) / (1.0 - a2);
+    microfacet_normal(n, cos2.max(0.0).sqrt(), u2)
+}
+
+impl Principled {
+    /// Index of refraction at `wavelength` nanometers from Cauchy's equation,
+    /// fitted so that `ior` is the value at the Fraunhofer d line and the
+    /// dispersion between the F and C lines matches `abbe`.
+    fn ior_at(&self, wavelength: Option<f32>) -> f32 {
+        match wavelength {
+            Some(l) if self.abbe > 0.0 => {
+                const D: f32 = 0.5876;
+                const F: f32 = 0.4861;
+                const C: f32 = 0.6563;
+                let b = (self.ior - 1.0) / (self.abbe * (1.0 / (F * F) - 1.0 / (C * C)));
+                let a = self.ior - b / (D * D);
+                let micrometers = l * 0.001;
+                a + b / (micrometers * micrometers)
+            }
+            _ => self.ior,
+        }
+    }
+
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-3)
+    }
+
+    fn clearcoat_alpha(&self) -> f32 {
+        0.1 + (0.001 - 0.1) * self.clearcoat_gloss
+    }
+
+    /// Base color normalized to unit luminance, used for the tint options.
+    fn tint(&self) -> Vec3 {
+        if self.base_color.luminance() > 0.0 {
+            self.base_color.scale(1.0 / self.base_color.luminance())
+        } else {
+            Vec3::one()
+        }
+    }
+
+    /// Normal incidence reflectance of the specular lobe.
+    fn specular_f0(&self) -> Vec3 {
+        Vec3::one()
+            .lerp(self.tint(), self.specular_tint)
+            .scale(0.08 * self.specular)
+            .lerp(self.base_color, self.metallic)
+    }
+
+    fn sheen_color(&self) -> Vec3 {
+        Vec3::one()
+            .lerp(self.tint(), self.sheen_tint)
+            .scale(self.sheen)
+    }
+
+    /// Clearcoat strength with its Fresnel term at `cos_theta`.
+    fn clearcoat_weight(&self, cos_theta: f32) -> f32 {
+        0.25 * self.clearcoat * (0.04 + 0.96 * schlick_weight(cos_theta))
+    }
+
+    /// Probability of picking the transmission lobe when entering the
+    /// surface. The reflection lobes described by `eval` and `pdf` make up
+    /// the rest of the BSDF.
+    fn transmission_weight(&self) -> f32 {
+        (1.0 - self.metallic) * self.transmission
+    }
+
+    /// Probabilities of sampling the clearcoat, specular and diffuse
+    /// reflection lobes, roughly proportional to their albedo at `cos_o`.
+    fn lobe_probabilities(&self, cos_o: f32) -> [f32; 3] {
+        let coat = self.clearcoat_weight(cos_o);
+        let specular = schlick(self.specular_f0(), cos_o).luminance();
+        let diffuse = (1.0 - self.metallic) * self.base_color.add(self.sheen_color()).luminance();
+        let sum = coat + specular + diffuse;
+        if sum <= 0.0 {
+            return [0.0; 3];
+        }
+        [coat / sum, specular / sum, diffuse / sum]
+    }
+
+    /// Reflection part of the BSDF for the outgoing direction `wo` and
+    /// incident direction `wi`, both pointing away from the surface on the
+    /// side of `n`: the Disney diffuse with retro-reflection, the sheen,
+    /// the GGX specular and the GTR1 clearcoat lobes added together.
+    pub fn eval(&self, n: Vec3, wo: Vec3, wi: Vec3) -> Vec3 {
+        let cos_o = n.dot(wo);
+        let cos_i = n.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Vec3::zero();
+        }
+        let h = wo.add(wi).normalize();
+        let cos_h = n.dot(h);
+        let cos_d = wi.dot(h);
+        let masking =
+            |alpha: f32| smith_g1(cos_o, alpha) * smith_g1(cos_i, alpha) / (4.0 * cos_o * cos_i);
+
+        let alpha = self.alpha();
+        let specular =
+            schlick(self.specular_f0(), cos_d).scale(ggx_d(cos_h, alpha) * masking(alpha));
+        let coat =
+            self.clearcoat_weight(cos_d) * gtr1_d(cos_h, self.clearcoat_alpha()) * masking(0.25);
+
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let retro = |cos: f32| 1.0 + (fd90 - 1.0) * schlick_weight(cos);
+        let diffuse = self
+            .base_color
+            .scale(retro(cos_o) * retro(cos_i) / PI)
+            .add(self.sheen_color().scale(schlick_weight(cos_d)))
+            .scale(1.0 - self.metallic);
+
+        specular.add(diffuse).add(Vec3::one().scale(coat))
+    }
+
+    /// Solid angle density of the directions `sample_reflection` produces.
+    pub fn pdf(&self, n: Vec3, wo: Vec3, wi: Vec3) -> f32 {
+        let cos_o = n.dot(wo);
+        let cos_i = n.dot(wi);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return 0.0;
+        }
+        let h = wo.add(wi).normalize();
+        let cos_h = n.dot(h);
+        let o_dot_h = wo.dot(h);
+        // Reflecting about the microfacet normal converts its density.
+        let jacobian = cos_h / (4.0 * o_dot_h);
+
+        let [coat, specular, diffuse] = self.lobe_probabilities(cos_o);
+        coat * gtr1_d(cos_h, self.clearcoat_alpha()) * jacobian
+            + specular * ggx_d(cos_h, self.alpha()) * jacobian
+            + diffuse * cosine_hemisphere_pdf(cos_i)
+    }
+
+    /// Picks a reflection lobe by `lobe_probabilities` and samples an
+    /// incident direction from it. Directions below the surface are
+    /// rejected.
+    fn sample_reflection(
+        &self,
+        n: Vec3,
+        wo: Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Vec3, Event)> {
+        let [coat, specular, _] = self.lobe_probabilities(n.dot(wo));
+        let u = sampler.get_1d();
+        let (h, alpha) = if u < coat {
+            let alpha = self.clearcoat_alpha();
+            (sample_gtr1(n, alpha, sampler), alpha)
+        } else if u < coat + specular {
+            let alpha = self.alpha();
+            (sample_ggx(n, alpha, sampler), alpha)
+        } else {
+            let direction = cosine_hemisphere(n, sampler.get_2d()).direction;
+            return Some((direction.normalize(), Event::Diffuse));
+        };
+        let direction = reflect(wo.scale(-1.0), h);
+        if n.dot(direction) <= 0.0 {
+            return None;
+        }
+        Some((direction, reflection_event(alpha)))
+    }
+
+    /// Picks the transmission lobe with probability `transmission_weight`
+    /// and samples it, otherwise samples the reflection lobes and weights
+    /// the direction by `eval` over the combined `pdf` of all of them.
+    /// Paths inside the material only got there through the transmission
+    /// lobe, so they always leave through it.
+    pub fn scatter(
+        &self,
+        ray: Ray,
+        point: Vec3,
+        normal: Vec3,
+        wavelength: Option<f32>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Scatter> {
+        let v = ray.direction.normalize();
+        let entering = normal.dot(v) < 0.0;
+        let n = if entering { normal } else { normal.scale(-1.0) };
+
+        let u = sampler.get_1d();
+        let transmits = if entering {
+            u < self.transmission_weight()
+        } else {
+            self.transmission_weight() > 0.0
+        };
+        if transmits {
+            let alpha = self.alpha();
+            let ior = self.ior_at(wavelength);
+            let dispersive = wavelength.is_some() && self.abbe > 0.0;
+            let eta = if entering { 1.0 / ior } else { ior };
+            let h = sample_ggx(n, alpha, sampler);
+            let fresnel = fresnel_dielectric(-v.dot(h), eta);
+            if sampler.get_1d() < fresnel {
+                let direction = reflect(v, h);
+                return Some(Scatter {
+                    ray: Ray {
+                        origin: point,
+                        direction,
+                    },
+                    attenuation: Vec3::one(),
+                    dispersive,
+                    event: reflection_event(alpha),
+                });
+            }
+            let direction = refract(v, h, eta)?;
+            return Some(Scatter {
+                ray: Ray {
+                    origin: point,
+                    direction,
+                },
+                attenuation: self.base_color,
+                dispersive,
+                event: Event::Transmission,
+            });
+        }
+
+        if !entering {
+            return None;
+        }
+
+        let wo = v.scale(-1.0);
+        let (direction, event) = self.sample_reflection(n, wo, sampler)?;
+        let pdf = self.pdf(n, wo, direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(Scatter {
+            ray: Ray {
+                origin: point,
+                direction,
+            },
+            attenuation: self.eval(n, wo, direction).scale(n.dot(direction) / pdf),
+            dispersive: false,
+            event,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::RngKind;
+    use crate::sampler::SamplerKind;
+
+    fn sampler(seed: u32) -> Box<dyn Sampler> {
+        let mut sampler = SamplerKind::Independent.create(1, seed, RngKind::Pcg32);
+        sampler.start_pixel_sample(0, 0, 0);
+        sampler
+    }
+
+    /// One material per lobe, each with the base color of a bright but
+    /// physically plausible surface, as the Disney model is only roughly
+    /// energy conserving for a white base.
+    fn materials() -> Vec<Principled> {
+        let light = Vec3::new(0.8, 0.8, 0.8);
+        vec![
+            Principled::default(),
+            Principled {
+                base_color: light,
+                roughness: 1.0,
+                ..Principled::default()
+            },
+            Principled {
+                base_color: light,
+                roughness: 0.2,
+                specular: 1.0,
+                ..Principled::default()
+            },
+            Principled {
+                base_color: Vec3::new(0.95, 0.64, 0.54),
+                metallic: 1.0,
+                roughness: 0.4,
+                ..Principled::default()
+            },
+            Principled {
+                base_color: light,
+                clearcoat: 1.0,
+                clearcoat_gloss: 0.3,
+                ..Principled::default()
+            },
+            Principled {
+                base_color: Vec3::new(0.5, 0.1, 0.1),
+                sheen: 1.0,
+                sheen_tint: 0.0,
+                roughness: 0.8,
+                ..Principled::default()
+            },
+            Principled {
+                base_color: light,
+                transmission: 0.5,
+                roughness: 0.3,
+                ..Principled::default()
+            },
+        ]
+    }
+
+    /// Direction at `cos_theta` to the +z normal.
+    fn direction(cos_theta: f32, phi: f32) -> Vec3 {
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    /// Monte Carlo estimate of the albedo for light arriving along
+    /// `direction`, which points towards the surface at the origin.
+    fn albedo(material: &Principled, direction: Vec3, seed: u32) -> Vec3 {
+        const SAMPLES: usize = 100_000;
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction,
+        };
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let mut sampler = sampler(seed);
+        let mut albedo = Vec3::zero();
+        for _ in 0..SAMPLES {
+            if let Some(s) = material.scatter(ray, Vec3::zero(), normal, None, &mut *sampler) {
+                assert!(s.attenuation.is_finite());
+                albedo = albedo.add(s.attenuation);
+            }
+        }
+        albedo.scale(1.0 / SAMPLES as f32)
+    }
+
+    fn assert_albedo_at_most(material: usize, cos_o: f32, albedo: Vec3, max: f32) {
+        assert!(
+            albedo.x <= max && albedo.y <= max && albedo.z <= max,
+            "material {} reflects ({}, {}, {}) at cos {}",
+            material,
+            albedo.x,
+            albedo.y,
+            albedo.z,
+            cos_o
+        );
+    }
+
+    #[test]
+    fn reflects_at_most_the_incident_energy() {
+        for (i, material) in materials().iter().enumerate() {
+            for &cos_o in &[1.0, 0.5] {
+                let albedo = albedo(material, direction(cos_o, 0.3).scale(-1.0), i as u32);
+                assert_albedo_at_most(i, cos_o, albedo, 1.01);
+            }
+            // Like the Disney model, the retro-reflection of rough diffuse
+            // surfaces and the specular lobe on top of the diffuse one gain
+            // some energy at grazing angles.
+            let albedo = albedo(material, direction(0.1, 0.3).scale(-1.0), i as u32);
+            assert_albedo_at_most(i, 0.1, albedo, 1.2);
+        }
+    }
+
+    #[test]
+    fn microfacet_lobes_never_gain_energy() {
+        let lobes = [
+            Principled {
+                base_color: Vec3::one(),
+                metallic: 1.0,
+                roughness: 0.6,
+                ..Principled::default()
+            },
+            Principled {
+                base_color: Vec3::zero(),
+                specular: 0.0,
+                clearcoat: 1.0,
+                clearcoat_gloss: 0.0,
+                ..Principled::default()
+            },
+        ];
+        for (i, material) in lobes.iter().enumerate() {
+            for &cos_o in &[1.0, 0.5, 0.1] {
+                let albedo = albedo(material, direction(cos_o, 0.3).scale(-1.0), i as u32);
+                assert_albedo_at_most(i, cos_o, albedo, 1.01);
+            }
+        }
+    }
+
+    /// Paths leaving a partly transmissive material go through the dielectric
+    /// interface, which for a white base loses no energy.
+    #[test]
+    fn exits_through_the_transmission_lobe() {
+        let material = Principled {
+            base_color: Vec3::one(),
+            transmission: 0.5,
+            roughness: 0.3,
+            ..Principled::default()
+        };
+        let albedo = albedo(&material, direction(0.8, 0.3), 7);
+        assert!((albedo.x - 1.0).abs() < 0.01, "exit albedo {}", albedo.x);
+    }
+
+    #[test]
+    fn reflection_is_reciprocal() {
+        let n = Vec3::new(0.2, -0.4, 0.9).normalize();
+        let mut sampler = sampler(3);
+        for material in materials() {
+            for _ in 0..1000 {
+                let upper = |d: Vec3| if d.dot(n) < 0.0 { d.scale(-1.0) } else { d };
+                let wo = upper(sampler.unit());
+                let wi = upper(sampler.unit());
+                let a = material.eval(n, wo, wi);
+                let b = material.eval(n, wi, wo);
+                assert!(
+                    a.sub(b).magnitude() <= 1e-4 * a.magnitude().max(1.0),
+                    "f(wo, wi) = {}, f(wi, wo) = {}",
+                    a.magnitude(),
+                    b.magnitude()
+                );
+            }
+        }
+    }
+
+    /// Bins sampled reflection directions over the hemisphere and compares
+    /// the counts with the integral of `pdf` over each bin, which only
+    /// matches if `pdf` uses the same lobe probabilities as the sampling.
+    #[test]
+    fn pdf_matches_sampled_directions() {
+        const SAMPLES: usize = 200_000;
+        const THETA_BINS: usize = 10;
+        const PHI_BINS: usize = 8;
+        const STEPS: usize = 24;
+
+        let n = Vec3::new(0.0, 0.0, 1.0);
+        let wo = direction(0.6, 0.0);
+        let material = Principled {
+            base_color: Vec3::new(0.6, 0.3, 0.2),
+            roughness: 0.6,
+            specular: 1.0,
+            clearcoat: 1.0,
+            clearcoat_gloss: 0.0,
+            ..Principled::default()
+        };
+        let bin = |d: Vec3| {
+            let theta = ((d.z * THETA_BINS as f32) as usize).min(THETA_BINS - 1);
+            let phi = d.y.atan2(d.x).rem_euclid(2.0 * PI);
+            let phi = ((phi / (2.0 * PI) * PHI_BINS as f32) as usize).min(PHI_BINS - 1);
+            theta * PHI_BINS + phi
+        };
+
+        let mut counts = vec![0usize; THETA_BINS * PHI_BINS];
+        let mut sampler = sampler(5);
+        for _ in 0..SAMPLES {
+            if let Some((wi, _)) = material.sample_reflection(n, wo, &mut *sampler) {
+                counts[bin(wi)] += 1;
+            }
+        }
+
+        let d_cos = 1.0 / (THETA_BINS * STEPS) as f32;
+        let d_phi = 2.0 * PI / (PHI_BINS * STEPS) as f32;
+        let mut expected = vec![0.0f32; THETA_BINS * PHI_BINS];
+        for i in 0..THETA_BINS * STEPS {
+            for j in 0..PHI_BINS * STEPS {
+                let wi = direction((i as f32 + 0.5) * d_cos, (j as f32 + 0.5) * d_phi);
+                expected[bin(wi)] += material.pdf(n, wo, wi) * d_cos * d_phi;
+            }
+        }
+
+        for (count, expected) in counts.iter().zip(&expected) {
+            let observed = *count as f32 / SAMPLES as f32;
+            let sigma = (expected / SAMPLES as f32).sqrt();
+            assert!(
+                (observed - expected).abs() <= 5.0 * sigma + 2e-3,
+                "observed {}, expected {}",
+                observed,
+                expected
+            );
+        }
+    }
+}
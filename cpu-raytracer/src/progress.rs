@@ -0,0 +1,155 @@
+//! Progress of a render as the worker threads finish their work, passed to
+//! an optional callback in `Settings`. The command line uses it for a
+//! progress bar on stderr.
+
+use crate::render::Settings;
+use crate::stats::Stats;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Callbacks are called at most this often, apart from the final report.
+const REPORT_INTERVAL_MS: u64 = 100;
+
+#[derive(Copy, Clone)]
+pub struct Progress {
+    /// Units of work finished and known so far. A unit is a pixel sample,
+    /// a Metropolis mutation or a photon, depending on the integrator. The
+    /// total grows when adaptive passes are added.
+    pub done: u64,
+    pub total: u64,
+    /// Rays traced so far.
+    pub rays: u64,
+    pub elapsed: Duration,
+    /// Set on the last report of a render only.
+    pub finished: bool,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        (self.done as f64 / self.total as f64) as f32
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        self.rays as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    /// Time left if the work continues at the rate so far.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done == 0 {
+            return None;
+        }
+        let left = self.total.saturating_sub(self.done) as f64 / self.done as f64;
+        Some(self.elapsed.mul_f64(left))
+    }
+}
+
+/// Called with the progress of a render, possibly from several worker
+/// threads at once.
+pub type ProgressCallback = dyn Fn(&Progress) + Sync;
+
+/// Sums up the work and statistics of all threads of a render and passes
+/// the progress on to the callback of its settings.
+pub struct Reporter<'a> {
+    callback: Option<&'a ProgressCallback>,
+    start: Instant,
+    done: AtomicU64,
+    total: AtomicU64,
+    stats: Mutex<Stats>,
+    /// Milliseconds after `start` of the last report.
+    reported: AtomicU64,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(settings: &'a Settings) -> Reporter<'a> {
+        Reporter {
+            callback: settings.progress.as_deref(),
+            start: Instant::now(),
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            stats: Mutex::new(Stats::default()),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// Announces `units` more units of work.
+    pub fn add_work(&self, units: u64) {
+        self.total.fetch_add(units, Ordering::Relaxed);
+    }
+
+    /// Records `units` of finished work and the statistics of the thread
+    /// while doing it.
+    pub fn advance(&self, units: u64, stats: &Stats) {
+        self.done.fetch_add(units, Ordering::Relaxed);
+        self.stats.lock().unwrap().add(stats);
+        if self.callback.is_none() {
+            return;
+        }
+        let now = self.start.elapsed().as_millis() as u64;
+        let last = self.reported.load(Ordering::Relaxed);
+        // Only one of the threads passing the interval at once reports.
+        if now >= last + REPORT_INTERVAL_MS
+            && self
+                .reported
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.report(false);
+        }
+    }
+
+    /// Sends the final report, once all work is done, and returns the
+    /// statistics of the whole render.
+    pub fn finish(&self) -> Stats {
+        self.report(true);
+        *self.stats.lock().unwrap()
+    }
+
+    fn report(&self, finished: bool) {
+        if let Some(callback) = self.callback {
+            callback(&Progress {
+                done: self.done.load(Ordering::Relaxed),
+                total: self.total.load(Ordering::Relaxed),
+                rays: self.stats.lock().unwrap().rays,
+                elapsed: self.start.elapsed(),
+                finished,
+            });
+        }
+    }
+}
+
+/// Redraws a progress bar with the ray rate and the time left on the
+/// current line of stderr.
+pub fn print_bar(progress: &Progress) {
+    const WIDTH: usize = 30;
+    let filled = ((progress.fraction() * WIDTH as f32) as usize).min(WIDTH);
+    let eta = match progress.eta() {
+        _ if progress.finished => format!("took {}", format_duration(progress.elapsed)),
+        Some(eta) => format!("ETA {}", format_duration(eta)),
+        None => "ETA --".to_string(),
+    };
+    eprint!(
+        "\r[{}{}] {:5.1}% {:7.2} Mrays/s {:<12}",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        progress.fraction() * 100.0,
+        progress.rays_per_second() / 1e6,
+        eta
+    );
+    if progress.finished {
+        eprintln!();
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let seconds = d.as_secs();
+    if seconds >= 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{:.1}s", d.as_secs_f32())
+    }
+}
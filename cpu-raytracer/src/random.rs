@@ -1,5 +1,67 @@
 use crate::Vec3;
 
+/// Pseudo random number generator.
+///
+/// Floats are built from the 24 most significant bits of `next_u32`, which
+/// gives every representable value in [0, 1) on a uniform 2^-24 grid and can
+/// never round up to 1.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+
+    /// Restarts the generator as if it had been created from `seed`.
+    fn reseed(&mut self, seed: u64);
+
+    /// Uniform value in [0, 1).
+    fn uni(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+
+    /// Uniform value in [-1, 1).
+    fn bi(&mut self) -> f32 {
+        self.uni() * 2.0 - 1.0
+    }
+
+    /// Uniformly distributed direction on the unit sphere.
+    fn unit(&mut self) -> Vec3 {
+        let a = self.uni() * 2.0 * std::f32::consts::PI;
+        let z = self.bi();
+        let r = (1.0 - z * z).sqrt();
+        Vec3 {
+            x: r * a.cos(),
+            y: r * a.sin(),
+            z,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum RngKind {
+    XorShift,
+    Pcg32,
+    Xoshiro128,
+}
+
+impl RngKind {
+    pub fn from_name(name: &str) -> Option<RngKind> {
+        Some(match name {
+            "xorshift" => RngKind::XorShift,
+            "pcg32" => RngKind::Pcg32,
+            "xoshiro128" => RngKind::Xoshiro128,
+            _ => return None,
+        })
+    }
+
+    pub fn create(&self, seed: u64) -> Box<dyn Rng + Send> {
+        match self {
+            RngKind::XorShift => Box::new(RngXorShift::from_seed(seed)),
+            RngKind::Pcg32 => Box::new(Pcg32::new(seed, 0)),
+            RngKind::Xoshiro128 => Box::new(Xoshiro128::new(seed)),
+        }
+    }
+}
+
+/// 32-bit xorshift. Small and fast, but with a short period and weak low
+/// bits; prefer `Pcg32` or `Xoshiro128` where sample quality matters.
 pub struct RngXorShift {
     seed: u32,
 }
@@ -9,33 +71,240 @@ impl RngXorShift {
         Self { seed }
     }
 
-    fn xor_shift(&mut self) -> u32 {
+    /// Seeds from an arbitrary 64-bit value, which may be zero.
+    pub fn from_seed(seed: u64) -> Self {
+        let seed = SplitMix64::new(seed).next_u64() as u32;
+        Self::new(seed.max(1))
+    }
+}
+
+impl Rng for RngXorShift {
+    fn reseed(&mut self, seed: u64) {
+        *self = Self::from_seed(seed);
+    }
+
+    fn next_u32(&mut self) -> u32 {
         self.seed ^= self.seed << 13;
         self.seed ^= self.seed >> 17;
         self.seed ^= self.seed << 5;
         self.seed
     }
+}
 
-    pub fn uni(&mut self) -> f32 {
-        self.xor_shift();
-        let f: f32 = unsafe { std::mem::transmute((self.seed & 0x007fffff) | 0x40000000) };
-        return (f - 2.0) * 0.5;
+/// SplitMix64, mainly used to expand seeds for the other generators.
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
     }
 
-    pub fn bi(&mut self) -> f32 {
-        self.xor_shift();
-        let f: f32 = unsafe { std::mem::transmute((self.seed & 0x007fffff) | 0x40000000) };
-        return f - 3.0;
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
     }
+}
 
-    pub fn unit(&mut self) -> Vec3 {
-        let a = self.uni() * 2.0 * std::f32::consts::PI;
-        let z = self.bi();
-        let r = (1.0 - z * z).sqrt();
-        Vec3 {
-            x: r * a.cos(),
-            y: r * a.sin(),
-            z,
+impl Rng for SplitMix64 {
+    fn reseed(&mut self, seed: u64) {
+        self.state = seed;
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+}
+
+/// PCG32 (XSH RR variant) from O'Neill, "PCG: A Family of Simple Fast
+/// Space-Efficient Statistically Good Algorithms for Random Number
+/// Generation" (2014). `sequence` selects one of 2^63 independent streams.
+pub struct Pcg32 {
+    state: u64,
+    increment: u64,
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, sequence: u64) -> Self {
+        let mut rng = Pcg32 {
+            state: 0,
+            increment: (sequence << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+}
+
+impl Rng for Pcg32 {
+    /// Keeps the current stream.
+    fn reseed(&mut self, seed: u64) {
+        *self = Pcg32::new(seed, self.increment >> 1);
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(0x5851_f42d_4c95_7f2d)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+}
+
+/// xoshiro128** from Blackman and Vigna, "Scrambled Linear Pseudorandom
+/// Number Generators" (2018).
+pub struct Xoshiro128 {
+    s: [u32; 4],
+}
+
+impl Xoshiro128 {
+    pub fn new(seed: u64) -> Self {
+        let mut splitmix = SplitMix64::new(seed);
+        let a = splitmix.next_u64();
+        let b = splitmix.next_u64();
+        Xoshiro128 {
+            s: [a as u32, (a >> 32) as u32, b as u32, (b >> 32) as u32],
+        }
+    }
+}
+
+impl Rng for Xoshiro128 {
+    fn reseed(&mut self, seed: u64) {
+        *self = Xoshiro128::new(seed);
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 9;
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(11);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: usize = 200_000;
+
+    fn generators() -> Vec<(&'static str, Box<dyn Rng>)> {
+        vec![
+            ("xorshift", Box::new(RngXorShift::from_seed(7))),
+            ("splitmix64", Box::new(SplitMix64::new(7))),
+            ("pcg32", Box::new(Pcg32::new(7, 3))),
+            ("xoshiro128", Box::new(Xoshiro128::new(7))),
+        ]
+    }
+
+    #[test]
+    fn reseed_restarts_the_sequence() {
+        for (name, mut rng) in generators() {
+            rng.reseed(42);
+            let first: Vec<u32> = (0..8).map(|_| rng.next_u32()).collect();
+            rng.reseed(43);
+            let other: Vec<u32> = (0..8).map(|_| rng.next_u32()).collect();
+            rng.reseed(42);
+            let again: Vec<u32> = (0..8).map(|_| rng.next_u32()).collect();
+            assert_eq!(first, again, "{}", name);
+            assert_ne!(first, other, "{}", name);
+        }
+    }
+
+    #[test]
+    fn uni_is_in_unit_interval_with_uniform_moments() {
+        for (name, mut rng) in generators() {
+            let mut sum = 0.0f64;
+            let mut sum_sq = 0.0f64;
+            for _ in 0..SAMPLES {
+                let u = rng.uni();
+                assert!((0.0..1.0).contains(&u), "{}: {} outside [0, 1)", name, u);
+                sum += u as f64;
+                sum_sq += (u as f64) * (u as f64);
+            }
+            let mean = sum / SAMPLES as f64;
+            let variance = sum_sq / SAMPLES as f64 - mean * mean;
+
+            // Five standard errors of the estimators for U(0, 1).
+            let mean_tolerance = 5.0 * (1.0 / 12.0 / SAMPLES as f64).sqrt();
+            let variance_tolerance = 5.0 * (1.0 / 180.0 / SAMPLES as f64).sqrt();
+            assert!(
+                (mean - 0.5).abs() < mean_tolerance,
+                "{}: mean {}",
+                name,
+                mean
+            );
+            assert!(
+                (variance - 1.0 / 12.0).abs() < variance_tolerance,
+                "{}: variance {}",
+                name,
+                variance
+            );
+        }
+    }
+
+    #[test]
+    fn bi_is_in_signed_unit_interval() {
+        for (name, mut rng) in generators() {
+            let mut sum = 0.0f64;
+            for _ in 0..SAMPLES {
+                let b = rng.bi();
+                assert!((-1.0..1.0).contains(&b), "{}: {} outside [-1, 1)", name, b);
+                sum += b as f64;
+            }
+            let mean = sum / SAMPLES as f64;
+            let tolerance = 5.0 * (1.0 / 3.0 / SAMPLES as f64).sqrt();
+            assert!(mean.abs() < tolerance, "{}: mean {}", name, mean);
+        }
+    }
+
+    #[test]
+    fn unit_is_uniform_over_sphere() {
+        // Equal area bins: uniform bands in z times uniform sectors in phi.
+        const BANDS: usize = 8;
+        const SECTORS: usize = 16;
+        const BINS: usize = BANDS * SECTORS;
+
+        for (name, mut rng) in generators() {
+            let mut counts = [0usize; BINS];
+            for _ in 0..SAMPLES {
+                let v = rng.unit();
+                assert!(
+                    (v.magnitude() - 1.0).abs() < 1e-4,
+                    "{}: length {}",
+                    name,
+                    v.magnitude()
+                );
+                let band = (((v.z + 1.0) * 0.5 * BANDS as f32) as usize).min(BANDS - 1);
+                let phi = v.y.atan2(v.x) + std::f32::consts::PI;
+                let sector = ((phi / (2.0 * std::f32::consts::PI) * SECTORS as f32) as usize)
+                    .min(SECTORS - 1);
+                counts[band * SECTORS + sector] += 1;
+            }
+
+            let expected = SAMPLES as f64 / BINS as f64;
+            let chi_square: f64 = counts
+                .iter()
+                .map(|c| {
+                    let d = *c as f64 - expected;
+                    d * d / expected
+                })
+                .sum();
+
+            // Critical value of the chi-square distribution with 127
+            // degrees of freedom at p = 0.001.
+            assert!(chi_square < 181.99, "{}: chi-square {}", name, chi_square);
         }
     }
 }
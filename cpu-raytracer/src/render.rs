@@ -0,0 +1,592 @@
+use crate::camera::Camera;
+use crate::film::{Aovs, Crop, Film, FilmTile, SplatBuffer};
+use crate::filter::Filter;
+use crate::integrator::{Integrator, Registration, SampleOutput, Technique, INTEGRATORS};
+use crate::lpe::{LightPathExpression, PathRecord};
+use crate::progress::{ProgressCallback, Reporter};
+use crate::random::RngKind;
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::scheduler::{self, TaskTiming, TileOrder};
+use crate::stats;
+use crate::vec3::Vec3;
+
+pub struct Settings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub sampler: SamplerKind,
+    pub rng: RngKind,
+    pub filter: Filter,
+    pub integrator: &'static Registration,
+    /// Trace a hero wavelength per path instead of RGB. Only supported by
+    /// the path integrator.
+    pub spectral: bool,
+    /// Global seed; every pixel sample derives its own seed from this, the
+    /// pixel coordinate and the sample index.
+    pub seed: u32,
+    pub threads: u32,
+    /// Width and height of the square tiles the image is split into.
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    /// Extra passes of `samples` samples each, spent only on pixels whose
+    /// relative error is still above `adaptive_threshold`.
+    pub adaptive_passes: usize,
+    pub adaptive_threshold: f32,
+    /// Each expression gets its own output buffer on the film.
+    pub light_paths: Vec<LightPathExpression>,
+    /// Independent paths traced to normalize the Metropolis integrator.
+    pub mlt_bootstrap: usize,
+    /// Markov chains the Metropolis integrator splits its mutations over.
+    pub mlt_chains: usize,
+    /// Probability of a Metropolis mutation replacing the whole path.
+    pub mlt_large_step: f32,
+    /// Photons traced per photon mapping iteration.
+    pub sppm_photons: usize,
+    /// Initial gather radius of the photon mapping integrator.
+    pub sppm_radius: f32,
+    /// Distance within which geometry occludes for ambient occlusion.
+    pub ao_distance: f32,
+    /// Only render the pixels inside this window.
+    pub crop: Option<Crop>,
+    /// Camera samples brighter than this are scaled down to it.
+    pub max_luminance: f32,
+    /// Print the pixel and path of every sample dropped for not being
+    /// finite.
+    pub log_nonfinite: bool,
+    /// Called as the render progresses.
+    pub progress: Option<Box<ProgressCallback>>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            width: 1920,
+            height: 1080,
+            samples: 256,
+            sampler: SamplerKind::Independent,
+            rng: RngKind::XorShift,
+            filter: Filter::Box { radius: 0.5 },
+            integrator: &INTEGRATORS[0],
+            spectral: false,
+            seed: 58727590,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            tile_size: 64,
+            tile_order: TileOrder::Scanline,
+            adaptive_passes: 0,
+            adaptive_threshold: 0.05,
+            light_paths: Vec::new(),
+            mlt_bootstrap: 100_000,
+            mlt_chains: 1000,
+            mlt_large_step: 0.3,
+            sppm_photons: 100_000,
+            sppm_radius: 0.025,
+            ao_distance: 0.25,
+            crop: None,
+            max_luminance: f32::INFINITY,
+            log_nonfinite: false,
+            progress: None,
+        }
+    }
+}
+
+/// How long rendering a tile took in one pass of `render`.
+pub struct TileTiming {
+    /// Zero for the initial pass, then one per adaptive pass.
+    pub pass: usize,
+    pub x_min: usize,
+    pub x_max: usize,
+    pub y_min: usize,
+    pub y_max: usize,
+    pub timing: TaskTiming,
+}
+
+/// A tile of a pass, with the samples rendered into it so far.
+pub struct Tile {
+    pub y_min: usize,
+    pub y_max: usize,
+    pub x_min: usize,
+    pub x_max: usize,
+    pub film: FilmTile,
+}
+
+/// Renders `scene` into a new film. The result only depends on the settings
+/// and not on the thread count or the order tiles finish in.
+///
+/// After the initial pass every adaptive pass revisits the pixels that have
+/// not yet converged, continuing their sample sequences where they left off.
+///
+/// With a crop window only the pixels inside it are sampled, and only the
+/// tiles overlapping it are rendered. The film keeps its full size with
+/// everything outside the window left empty.
+pub fn render(scene: &Scene, camera: &Camera, settings: &Settings) -> Film {
+    let mut integrator = match settings.integrator.technique {
+        Technique::Sampled(create) => create(camera, settings),
+        Technique::Image(render) => return render(scene, camera, settings),
+    };
+    integrator.preprocess(scene);
+    let integrator = integrator.as_ref();
+
+    render_passes(settings, |progress, film, taken, wanted, pass| {
+        render_pass(
+            scene, camera, settings, integrator, progress, film, taken, wanted, pass,
+        )
+    })
+}
+
+/// Runs the passes of `render`, leaving the sampling of each pass to
+/// `render_pass`. It is called with the film so far, the samples already
+/// taken and the samples wanted per pixel, and the index of the pass.
+pub fn render_passes<F>(settings: &Settings, mut render_pass: F) -> Film
+where
+    F: FnMut(&Reporter, &mut Film, &[usize], &[usize], usize),
+{
+    let progress = Reporter::new(settings);
+
+    let names = settings
+        .light_paths
+        .iter()
+        .map(|e| e.name.clone())
+        .collect();
+    let mut film = Film::new(settings.width, settings.height, settings.filter, names);
+    let mut taken = vec![0; settings.width * settings.height];
+    let inside = |i: usize| {
+        let (x, y) = (i % settings.width, i / settings.width);
+        settings.crop.is_none_or(|c| c.contains(x, y))
+    };
+    let mut wanted: Vec<usize> = (0..settings.width * settings.height)
+        .map(|i| if inside(i) { settings.samples } else { 0 })
+        .collect();
+
+    render_pass(&progress, &mut film, &taken, &wanted, 0);
+
+    for pass in 1..=settings.adaptive_passes {
+        for (i, (t, w)) in taken.iter_mut().zip(wanted.iter_mut()).enumerate() {
+            *t += *w;
+            let (x, y) = (i % settings.width, i / settings.width);
+            *w = if inside(i) && film.relative_error(x, y) > settings.adaptive_threshold {
+                settings.samples
+            } else {
+                0
+            };
+        }
+        if wanted.iter().all(|w| *w == 0) {
+            break;
+        }
+        render_pass(&progress, &mut film, &taken, &wanted, pass);
+    }
+
+    if let Some(crop) = &settings.crop {
+        film.clear_outside(crop);
+    }
+    film.stats = progress.finish();
+    film
+}
+
+/// Factor that scales `sample` down to a luminance of at most
+/// `max_luminance`, trading some energy for fewer fireflies.
+pub fn firefly_scale(sample: Vec3, max_luminance: f32) -> f32 {
+    let luminance = sample.luminance();
+    if luminance <= max_luminance {
+        return 1.0;
+    }
+    stats::count(|s| s.clamped_samples += 1);
+    max_luminance / luminance
+}
+
+/// Takes `wanted[i]` more samples for every pixel `i`, starting at sample
+/// index `taken[i]`. Tiles without any wanted samples are skipped.
+#[allow(clippy::too_many_arguments)]
+fn render_pass(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &Settings,
+    integrator: &dyn Integrator,
+    progress: &Reporter,
+    film: &mut Film,
+    taken: &[usize],
+    wanted: &[usize],
+    pass: usize,
+) {
+    progress.add_work(wanted.iter().sum::<usize>() as u64);
+    let (mut tiles, order) = plan_tiles(settings, film, wanted);
+    let timings = render_tiles(
+        scene,
+        camera,
+        settings,
+        integrator,
+        progress,
+        film.splats(),
+        &mut tiles,
+        &order,
+        taken,
+        wanted,
+    );
+
+    // Merge in tile order so floating point sums are identical run to run.
+    for (t, timing) in tiles.iter().zip(timings) {
+        film.merge(&t.film);
+        film.tile_timings.push(TileTiming {
+            pass,
+            x_min: t.x_min,
+            x_max: t.x_max,
+            y_min: t.y_min,
+            y_max: t.y_max,
+            timing,
+        });
+    }
+}
+
+/// The tiles of a pass that have any wanted samples, in scanline order,
+/// which they are merged in, and the order of their indices they are
+/// scheduled in.
+pub fn plan_tiles(settings: &Settings, film: &Film, wanted: &[usize]) -> (Vec<Tile>, Vec<usize>) {
+    let width = settings.width;
+    let height = settings.height;
+    let tile_size = settings.tile_size;
+    let tile_count_x = width.div_ceil(tile_size);
+    let tile_count_y = height.div_ceil(tile_size);
+
+    let mut rank = vec![0; tile_count_x * tile_count_y];
+    for (i, (x, y)) in settings
+        .tile_order
+        .tiles(tile_count_x, tile_count_y)
+        .into_iter()
+        .enumerate()
+    {
+        rank[y * tile_count_x + x] = i;
+    }
+
+    let mut tiles: Vec<Tile> = Vec::with_capacity(tile_count_x * tile_count_y);
+    let mut order = Vec::with_capacity(tile_count_x * tile_count_y);
+
+    for y in 0..tile_count_y {
+        let y_min = y * tile_size;
+        let y_max = std::cmp::min(y_min + tile_size, height);
+        for x in 0..tile_count_x {
+            let x_min = x * tile_size;
+            let x_max = std::cmp::min(x_min + tile_size, width);
+
+            let has_work = (y_min..y_max).any(|y| {
+                wanted[y * width + x_min..y * width + x_max]
+                    .iter()
+                    .any(|w| *w > 0)
+            });
+            if !has_work {
+                continue;
+            }
+
+            order.push((rank[y * tile_count_x + x], tiles.len()));
+            tiles.push(Tile {
+                y_min,
+                y_max,
+                x_min,
+                x_max,
+                film: film.tile(x_min, x_max, y_min, y_max),
+            });
+        }
+    }
+    order.sort();
+    (tiles, order.into_iter().map(|(_, i)| i).collect())
+}
+
+/// Samples the tiles in `order` on the threads of the settings, as in
+/// `render_pass`, and returns their timings.
+#[allow(clippy::too_many_arguments)]
+pub fn render_tiles(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &Settings,
+    integrator: &dyn Integrator,
+    progress: &Reporter,
+    splats: &SplatBuffer,
+    tiles: &mut [Tile],
+    order: &[usize],
+    taken: &[usize],
+    wanted: &[usize],
+) -> Vec<TaskTiming> {
+    let width = settings.width;
+    let height = settings.height;
+
+    // Samplers that stratify over the pixel's sample count need to know
+    // the most samples any pixel can receive.
+    let max_samples = settings.samples * (settings.adaptive_passes + 1);
+
+    let threads = settings.threads as usize;
+    scheduler::run(tiles, order, threads, |t| {
+        let mut sampler = settings
+            .sampler
+            .create(max_samples, settings.seed, settings.rng);
+        let sampler = sampler.as_mut();
+        let start = stats::snapshot();
+        let mut samples = 0;
+
+        for y in (t.y_min..t.y_max).rev() {
+            for x in t.x_min..t.x_max {
+                let first = taken[y * width + x];
+                samples += wanted[y * width + x];
+                for s in first..first + wanted[y * width + x] {
+                    sampler.start_pixel_sample(x, y, s);
+                    let (jitter_x, jitter_y) = sampler.get_2d();
+                    let film_x = x as f32 + jitter_x;
+                    let film_y = y as f32 + jitter_y;
+                    let ray = camera.ray_from_uv(film_x / width as f32, film_y / height as f32);
+                    stats::count(|s| s.primary_rays += 1);
+
+                    let mut output = SampleOutput {
+                        aovs: Aovs::none(),
+                        path: if settings.light_paths.is_empty() && !settings.log_nonfinite {
+                            None
+                        } else {
+                            Some(PathRecord::new())
+                        },
+                        splats,
+                    };
+                    let sample = integrator.li(ray, scene, sampler, &mut output);
+                    if !sample.is_finite() {
+                        stats::count(|s| s.nonfinite_samples += 1);
+                        if settings.log_nonfinite {
+                            let path = output.path.map(|p| p.describe()).unwrap_or_default();
+                            eprintln!(
+                                "Dropped non-finite sample {} of pixel ({}, {}) along path {}",
+                                s, x, y, path
+                            );
+                        }
+                        continue;
+                    }
+                    let scale = firefly_scale(sample, settings.max_luminance);
+                    let sample = sample.scale(scale);
+                    let light_paths: Vec<Vec3> = output.path.map_or(Vec::new(), |p| {
+                        let light_paths = settings.light_paths.iter();
+                        light_paths.map(|e| p.evaluate(e).scale(scale)).collect()
+                    });
+                    t.film
+                        .add_sample((x, y), film_x, film_y, sample, &light_paths);
+                    t.film.add_aovs((x, y), &output.aovs);
+                }
+            }
+        }
+        progress.advance(samples as u64, &stats::snapshot().since(&start));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Progress;
+    use crate::ray::Ray;
+    use crate::sampler::Sampler;
+    use std::sync::{Arc, Mutex};
+
+    fn render_with_threads(
+        sampler: SamplerKind,
+        threads: u32,
+        tile_order: TileOrder,
+        adaptive_passes: usize,
+    ) -> Film {
+        let settings = Settings {
+            width: 80,
+            height: 72,
+            samples: 4,
+            sampler,
+            filter: Filter::Gaussian {
+                radius: 1.5,
+                alpha: 2.0,
+            },
+            threads,
+            tile_size: 16,
+            tile_order,
+            adaptive_passes,
+            ..Settings::default()
+        };
+        let camera = Camera::look_at(
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.0, -0.25, -1.0),
+            std::f32::consts::FRAC_PI_2,
+            settings.width as f32 / settings.height as f32,
+        );
+        render(&Scene::cornell_box(), &camera, &settings)
+    }
+
+    fn assert_identical(a: &Film, b: &Film) {
+        for y in 0..a.height {
+            for x in 0..a.width {
+                let (pa, pb) = (a.pixel(x, y), b.pixel(x, y));
+                assert_eq!(
+                    [pa.x.to_bits(), pa.y.to_bits(), pa.z.to_bits()],
+                    [pb.x.to_bits(), pb.y.to_bits(), pb.z.to_bits()],
+                    "pixel ({}, {}) differs",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn render_is_independent_of_thread_count_and_tile_order() {
+        for sampler in [SamplerKind::Independent, SamplerKind::Sobol] {
+            let single = render_with_threads(sampler, 1, TileOrder::Scanline, 0);
+            for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+                let multi = render_with_threads(sampler, 3, order, 0);
+                assert_identical(&single, &multi);
+                assert_eq!(single.stats, multi.stats);
+            }
+        }
+    }
+
+    #[test]
+    fn adaptive_sampling_is_deterministic_and_focused() {
+        let single = render_with_threads(SamplerKind::Independent, 1, TileOrder::Scanline, 2);
+        let multi = render_with_threads(SamplerKind::Independent, 3, TileOrder::Spiral, 2);
+        assert_identical(&single, &multi);
+
+        let (mut min, mut max) = (u32::MAX, 0);
+        for y in 0..single.height {
+            for x in 0..single.width {
+                assert_eq!(single.sample_count(x, y), multi.sample_count(x, y));
+                min = min.min(single.sample_count(x, y));
+                max = max.max(single.sample_count(x, y));
+            }
+        }
+        assert_eq!(min, 4);
+        assert_eq!(max, 12);
+    }
+
+    #[test]
+    fn progress_reports_all_work_once_finished() {
+        let last = Arc::new(Mutex::new(None));
+        let reported = last.clone();
+        let settings = Settings {
+            width: 32,
+            height: 24,
+            samples: 2,
+            tile_size: 8,
+            threads: 3,
+            adaptive_passes: 1,
+            progress: Some(Box::new(move |p: &Progress| {
+                let mut last = reported.lock().unwrap();
+                assert!(last.is_none_or(|l: Progress| !l.finished));
+                *last = Some(*p);
+            })),
+            ..Settings::default()
+        };
+        let camera = Camera::look_at(
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.0, -0.25, -1.0),
+            std::f32::consts::FRAC_PI_2,
+            settings.width as f32 / settings.height as f32,
+        );
+        let film = render(&Scene::cornell_box(), &camera, &settings);
+
+        let last = last.lock().unwrap().expect("no progress reported");
+        assert!(last.finished);
+        let samples: u32 = (0..film.height)
+            .flat_map(|y| (0..film.width).map(move |x| (x, y)))
+            .map(|(x, y)| film.sample_count(x, y))
+            .sum();
+        assert_eq!(last.done, samples as u64);
+        assert_eq!(film.stats.primary_rays, samples as u64);
+        assert_eq!(last.rays, film.stats.rays);
+        assert_eq!(last.total, last.done);
+        assert!(last.rays >= last.done);
+    }
+
+    /// NaN for the left half of the image and far too bright elsewhere.
+    struct Faulty;
+
+    impl Integrator for Faulty {
+        fn li(&self, ray: Ray, _: &Scene, _: &mut dyn Sampler, _: &mut SampleOutput) -> Vec3 {
+            if ray.direction.x < 0.0 {
+                Vec3::new(f32::NAN, 0.0, 0.0)
+            } else {
+                Vec3::new(1e6, 1e6, 1e6)
+            }
+        }
+    }
+
+    static FAULTY: Registration = Registration {
+        name: "faulty",
+        technique: Technique::Sampled(|_, _| Box::new(Faulty)),
+    };
+
+    #[test]
+    fn nonfinite_samples_are_dropped_and_bright_ones_clamped() {
+        let settings = Settings {
+            width: 16,
+            height: 8,
+            samples: 2,
+            integrator: &FAULTY,
+            max_luminance: 10.0,
+            ..Settings::default()
+        };
+        let camera = Camera::look_at(
+            Vec3::zero(),
+            Vec3::new(0.0, 0.0, -1.0),
+            std::f32::consts::FRAC_PI_2,
+            2.0,
+        );
+        let film = render(&Scene::cornell_box(), &camera, &settings);
+
+        assert_eq!(film.stats.nonfinite_samples, 16 * 8);
+        assert_eq!(film.stats.clamped_samples, 16 * 8);
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let expected = if x < 8 { 0 } else { 2 };
+                assert_eq!(film.sample_count(x, y), expected);
+                let pixel = film.pixel(x, y);
+                assert!(pixel.is_finite());
+                assert!(pixel.luminance() <= 10.0 + 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn crop_renders_only_the_window() {
+        let render_crop = |crop: Option<Crop>| {
+            let settings = Settings {
+                width: 32,
+                height: 24,
+                samples: 2,
+                tile_size: 8,
+                crop,
+                ..Settings::default()
+            };
+            let camera = Camera::look_at(
+                Vec3::new(0.0, 0.0, 0.5),
+                Vec3::new(0.0, -0.25, -1.0),
+                std::f32::consts::FRAC_PI_2,
+                settings.width as f32 / settings.height as f32,
+            );
+            render(&Scene::cornell_box(), &camera, &settings)
+        };
+        let crop = Crop::from_image_rect(5, 3, 19, 11, 24);
+        let full = render_crop(None);
+        let cropped = render_crop(Some(crop));
+
+        // Tiles not overlapping columns 5..19 and rows 13..21 are skipped.
+        assert_eq!(cropped.tile_timings.len(), 3 * 2);
+        for y in 0..full.height {
+            for x in 0..full.width {
+                if crop.contains(x, y) {
+                    assert_eq!(
+                        full.pixel(x, y).x.to_bits(),
+                        cropped.pixel(x, y).x.to_bits()
+                    );
+                } else {
+                    assert_eq!(cropped.sample_count(x, y), 0);
+                    assert_eq!(cropped.pixel(x, y).x, 0.0);
+                }
+            }
+        }
+
+        let window = cropped.crop(&crop);
+        assert_eq!((window.width, window.height), (14, 8));
+        for y in 0..window.height {
+            for x in 0..window.width {
+                let p = full.pixel(x + crop.x_min, y + crop.y_min);
+                assert_eq!(window.pixel(x, y).x.to_bits(), p.x.to_bits());
+            }
+        }
+    }
+}
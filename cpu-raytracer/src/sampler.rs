@@ -0,0 +1,558 @@
+use crate::random::{Rng, RngKind, RngXorShift};
+use crate::sampling::uniform_sphere;
+use crate::vec3::Vec3;
+
+use std::sync::OnceLock;
+
+/// Source of the sample values consumed while tracing a pixel sample.
+///
+/// Each call to `get_1d` or `get_2d` advances to the next dimension, so a
+/// sampler can hand out well distributed values per dimension across the
+/// samples of a pixel.
+pub trait Sampler: Send {
+    /// Prepares the sampler for sample `index` of pixel (`x`, `y`).
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize);
+
+    fn get_1d(&mut self) -> f32;
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+
+    /// Uniformly distributed direction on the unit sphere.
+    fn unit(&mut self) -> Vec3 {
+        uniform_sphere(self.get_2d()).direction
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        Some(match name {
+            "independent" => SamplerKind::Independent,
+            "stratified" => SamplerKind::Stratified,
+            "halton" => SamplerKind::Halton,
+            "sobol" => SamplerKind::Sobol,
+            "bluenoise" => SamplerKind::BlueNoise,
+            _ => return None,
+        })
+    }
+
+    /// `rng` is only used by the independent sampler.
+    pub fn create(&self, samples_per_pixel: usize, seed: u32, rng: RngKind) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler {
+                seed,
+                rng: rng.create(seed as u64),
+            }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler {
+                samples_per_pixel,
+                state: PixelState::new(seed),
+            }),
+            SamplerKind::Halton => Box::new(HaltonSampler {
+                state: PixelState::new(seed),
+            }),
+            SamplerKind::Sobol => Box::new(SobolSampler {
+                state: PixelState::new(seed),
+            }),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler {
+                state: PixelState::new(seed),
+                x: 0,
+                y: 0,
+            }),
+        }
+    }
+}
+
+/// Hash of a list of words, used to derive decorrelated seeds for every
+/// pixel and dimension.
+pub fn hash(values: &[u32]) -> u32 {
+    let mut h: u32 = 0x811c_9dc5;
+    for v in values {
+        h = mix_bits(h ^ *v);
+    }
+    h
+}
+
+fn mix_bits(mut v: u32) -> u32 {
+    v ^= v >> 16;
+    v = v.wrapping_mul(0x7feb_352d);
+    v ^= v >> 15;
+    v = v.wrapping_mul(0x846c_a68b);
+    v ^= v >> 16;
+    v
+}
+
+fn to_unit_float(v: u32) -> f32 {
+    // Keep the 24 most significant bits so the result is exactly
+    // representable and strictly below 1.
+    (v >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+/// Per-pixel bookkeeping shared by the padded samplers.
+struct PixelState {
+    seed: u32,
+    pixel: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl PixelState {
+    fn new(seed: u32) -> PixelState {
+        PixelState {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn start(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel = hash(&[self.seed, x as u32, y as u32]);
+        self.index = index as u32;
+        self.dimension = 0;
+    }
+
+    /// Returns a seed unique to the current pixel and dimension and
+    /// advances to the next dimension.
+    fn next_dimension(&mut self) -> u32 {
+        let seed = hash(&[self.pixel, self.dimension]);
+        self.dimension += 1;
+        seed
+    }
+}
+
+/// Uncorrelated random values from a generator that is reseeded for every
+/// pixel sample.
+pub struct IndependentSampler {
+    seed: u32,
+    rng: Box<dyn Rng + Send>,
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        let pixel = hash(&[self.seed, x as u32, y as u32]) as u64;
+        self.rng.reseed((pixel << 32) | index as u64);
+    }
+
+    fn unit(&mut self) -> Vec3 {
+        self.rng.unit()
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.uni()
+    }
+}
+
+/// Random permutation of `i` in [0, l) selected by `p`, from Kensler,
+/// "Correlated Multi-Jittered Sampling" (2013).
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+/// Jittered samples where every dimension is stratified independently and
+/// the strata are shuffled per pixel and dimension.
+pub struct StratifiedSampler {
+    samples_per_pixel: usize,
+    state: PixelState,
+}
+
+impl StratifiedSampler {
+    fn stratum(&self, count: u32, seed: u32) -> u32 {
+        permute(self.state.index % count, count, seed)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let seed = self.state.next_dimension();
+        let count = self.samples_per_pixel as u32;
+        let stratum = self.stratum(count, seed);
+        let jitter = to_unit_float(hash(&[seed, self.state.index, 1]));
+        (stratum as f32 + jitter) / count as f32
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = self.state.next_dimension();
+        let nx = (self.samples_per_pixel as f32).sqrt().max(1.0) as u32;
+        let ny = (self.samples_per_pixel as u32).div_ceil(nx);
+        let stratum = self.stratum(nx * ny, seed);
+        let jx = to_unit_float(hash(&[seed, self.state.index, 1]));
+        let jy = to_unit_float(hash(&[seed, self.state.index, 2]));
+        (
+            ((stratum % nx) as f32 + jx) / nx as f32,
+            ((stratum / nx) as f32 + jy) / ny as f32,
+        )
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Radical inverse of `a` in `base` where each digit is randomly permuted
+/// depending on the digits before it, which is equivalent to Owen
+/// scrambling.
+fn owen_scrambled_radical_inverse(base: u32, mut a: u32, seed: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed: u64 = 0;
+    let mut digit_index = 0;
+    while 1.0 - (base - 1) as f32 * (inv_base_m as f32) < 1.0 {
+        let next = a / base;
+        let digit = a - next * base;
+        let shift = hash(&[seed, digit_index, reversed as u32]) % base;
+        let digit = (digit + shift) % base;
+        reversed = reversed * base as u64 + digit as u64;
+        inv_base_m *= inv_base;
+        digit_index += 1;
+        a = next;
+    }
+    ((reversed as f64 * inv_base_m) as f32).min(1.0 - f32::EPSILON)
+}
+
+/// Halton sequence over the sample index with Owen scrambling seeded per
+/// pixel and dimension.
+pub struct HaltonSampler {
+    state: PixelState,
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.dimension as usize;
+        let seed = self.state.next_dimension();
+        let base = PRIMES[dimension % PRIMES.len()];
+        owen_scrambled_radical_inverse(base, self.state.index, seed)
+    }
+}
+
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+fn sobol_1(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut v = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+/// Hash based nested uniform scrambling from Burley, "Practical Hash-based
+/// Owen Scrambling" (2020).
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+fn scrambled_sobol_2d(index: u32, seed: u32) -> (f32, f32) {
+    let index = nested_uniform_scramble(index, seed);
+    (
+        to_unit_float(nested_uniform_scramble(sobol_0(index), hash(&[seed, 0]))),
+        to_unit_float(nested_uniform_scramble(sobol_1(index), hash(&[seed, 1]))),
+    )
+}
+
+/// Padded 2D Owen-scrambled Sobol samples; every dimension (pair) gets its
+/// own shuffle and scramble seed so dimensions stay decorrelated.
+pub struct SobolSampler {
+    state: PixelState,
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let seed = self.state.next_dimension();
+        scrambled_sobol_2d(self.state.index, seed).0
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = self.state.next_dimension();
+        scrambled_sobol_2d(self.state.index, seed)
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Tileable blue noise ranks in [0, 1), generated once on first use by
+/// placing one point after another in the largest void of a Gaussian energy
+/// field. This is the ranking phase of Ulichney's void-and-cluster method
+/// without its initial binary pattern.
+fn blue_noise() -> &'static [f32] {
+    static TEXTURE: OnceLock<Vec<f32>> = OnceLock::new();
+    TEXTURE.get_or_init(|| {
+        const N: usize = BLUE_NOISE_SIZE;
+        const SIGMA: f32 = 1.9;
+        let radius = 6isize;
+
+        let mut energy = vec![0.0f32; N * N];
+        let mut rank = vec![0usize; N * N];
+        let mut filled = vec![false; N * N];
+
+        let splat = |energy: &mut Vec<f32>, p: usize, sign: f32| {
+            let (px, py) = ((p % N) as isize, (p / N) as isize);
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let x = (px + dx).rem_euclid(N as isize) as usize;
+                    let y = (py + dy).rem_euclid(N as isize) as usize;
+                    let d2 = (dx * dx + dy * dy) as f32;
+                    energy[y * N + x] += sign * (-d2 / (2.0 * SIGMA * SIGMA)).exp();
+                }
+            }
+        };
+
+        // Progressively place each point in the largest void, which
+        // yields the ranking directly without an initial pattern phase.
+        let mut rng = RngXorShift::new(0x2545_f491);
+        for r in 0..N * N {
+            let mut best = usize::MAX;
+            let mut best_energy = f32::MAX;
+            for p in 0..N * N {
+                if !filled[p] {
+                    let e = energy[p] + rng.uni() * 1e-4;
+                    if e < best_energy {
+                        best_energy = e;
+                        best = p;
+                    }
+                }
+            }
+            filled[best] = true;
+            rank[best] = r;
+            splat(&mut energy, best, 1.0);
+        }
+
+        rank.iter()
+            .map(|r| (*r as f32 + 0.5) / (N * N) as f32)
+            .collect()
+    })
+}
+
+/// Owen-scrambled Sobol samples shared by all pixels and decorrelated with a
+/// per-dimension toroidal shift of a blue noise mask, so the remaining error
+/// is distributed as blue noise across the image.
+pub struct BlueNoiseSampler {
+    state: PixelState,
+    x: usize,
+    y: usize,
+}
+
+impl BlueNoiseSampler {
+    fn offset(&self, seed: u32, channel: u32) -> f32 {
+        let shift = hash(&[seed, channel]) as usize;
+        let x = (self.x + shift) % BLUE_NOISE_SIZE;
+        let y = (self.y + (shift >> 8)) % BLUE_NOISE_SIZE;
+        blue_noise()[y * BLUE_NOISE_SIZE + x]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.x = x;
+        self.y = y;
+        self.state.index = index as u32;
+        self.state.pixel = self.state.seed;
+        self.state.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.get_2d().0
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = self.state.next_dimension();
+        let (u, v) = scrambled_sobol_2d(self.state.index, seed);
+        let u = (u + self.offset(seed, 0)).fract();
+        let v = (v + self.offset(seed, 1)).fract();
+        (u, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u32 = 17;
+
+    /// First 2D sample of `count` consecutive pixel samples.
+    fn points(sampler: &mut dyn Sampler, count: usize) -> Vec<(f32, f32)> {
+        (0..count)
+            .map(|i| {
+                sampler.start_pixel_sample(3, 5, i);
+                sampler.get_2d()
+            })
+            .collect()
+    }
+
+    /// Asserts that every cell of an `nx` by `ny` grid holds exactly one
+    /// point.
+    fn assert_one_per_cell(points: &[(f32, f32)], nx: usize, ny: usize) {
+        let mut counts = vec![0; nx * ny];
+        for (u, v) in points {
+            assert!((0.0..1.0).contains(u) && (0.0..1.0).contains(v));
+            counts[(v * ny as f32) as usize * nx + (u * nx as f32) as usize] += 1;
+        }
+        assert!(
+            counts.iter().all(|c| *c == 1),
+            "{}x{} cells: {:?}",
+            nx,
+            ny,
+            counts
+        );
+    }
+
+    #[test]
+    fn stratified_sampler_puts_one_sample_in_every_stratum() {
+        let mut sampler = SamplerKind::Stratified.create(16, SEED, RngKind::Pcg32);
+        assert_one_per_cell(&points(&mut *sampler, 16), 4, 4);
+
+        let values: Vec<(f32, f32)> = (0..16)
+            .map(|i| {
+                sampler.start_pixel_sample(3, 5, i);
+                (sampler.get_1d(), 0.0)
+            })
+            .collect();
+        assert_one_per_cell(&values, 16, 1);
+    }
+
+    #[test]
+    fn halton_sampler_is_stratified_in_its_bases() {
+        let mut sampler = SamplerKind::Halton.create(1, SEED, RngKind::Pcg32);
+        assert_one_per_cell(&points(&mut *sampler, 6), 2, 3);
+        assert_one_per_cell(&points(&mut *sampler, 36), 4, 9);
+    }
+
+    #[test]
+    fn sobol_sampler_is_a_net() {
+        let mut sampler = SamplerKind::Sobol.create(1, SEED, RngKind::Pcg32);
+        let points = points(&mut *sampler, 64);
+        for log_nx in 0..=6 {
+            assert_one_per_cell(&points, 1 << log_nx, 1 << (6 - log_nx));
+        }
+    }
+
+    #[test]
+    fn owen_scrambling_depends_on_the_seed() {
+        for kind in &[SamplerKind::Halton, SamplerKind::Sobol] {
+            let a = points(&mut *kind.create(1, SEED, RngKind::Pcg32), 16);
+            let b = points(&mut *kind.create(1, SEED + 1, RngKind::Pcg32), 16);
+            assert!(a.iter().zip(&b).all(|(a, b)| a != b));
+        }
+        assert_ne!(
+            owen_scrambled_radical_inverse(3, 5, 1),
+            owen_scrambled_radical_inverse(3, 5, 2)
+        );
+    }
+
+    /// `block_variance` of the first value each pixel of a 64 by 64 image
+    /// gets.
+    fn sampler_block_variance(kind: SamplerKind, block: usize) -> f32 {
+        let mut sampler = kind.create(1, SEED, RngKind::Pcg32);
+        let size = BLUE_NOISE_SIZE;
+        let mut values = vec![0.0; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                sampler.start_pixel_sample(x, y, 0);
+                values[y * size + x] = sampler.get_1d();
+            }
+        }
+        block_variance(&values, block)
+    }
+
+    /// Variance of the averages of `block` by `block` pixel blocks of a 64
+    /// by 64 image. Blue noise keeps neighbours apart, so it averages out
+    /// faster than white noise.
+    fn block_variance(values: &[f32], block: usize) -> f32 {
+        let size = BLUE_NOISE_SIZE;
+        let blocks = size / block;
+        let means: Vec<f32> = (0..blocks * blocks)
+            .map(|b| {
+                let (bx, by) = (b % blocks * block, b / blocks * block);
+                let sum: f32 = (0..block * block)
+                    .map(|i| values[(by + i / block) * size + bx + i % block])
+                    .sum();
+                sum / (block * block) as f32
+            })
+            .collect();
+        let mean = means.iter().sum::<f32>() / means.len() as f32;
+        means.iter().map(|m| (m - mean) * (m - mean)).sum::<f32>() / means.len() as f32
+    }
+
+    #[test]
+    fn blue_noise_averages_out_faster_than_white_noise() {
+        let mask = block_variance(blue_noise(), 4);
+        let blue = sampler_block_variance(SamplerKind::BlueNoise, 4);
+        let white = sampler_block_variance(SamplerKind::Independent, 4);
+        assert!(mask < 0.3 * white, "mask {}, white {}", mask, white);
+        // The per-dimension shift wraps some values around, which costs a
+        // little of the mask's advantage.
+        assert!(blue < 0.5 * white, "blue {}, white {}", blue, white);
+    }
+
+    #[test]
+    fn independent_sampler_repeats_a_pixel_sample() {
+        let mut sampler = SamplerKind::Independent.create(1, SEED, RngKind::Xoshiro128);
+        let first = points(&mut *sampler, 4);
+        let again = points(&mut *sampler, 4);
+        assert_eq!(first, again);
+        assert_ne!(first[0], first[1]);
+    }
+}
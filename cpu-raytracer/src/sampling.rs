@@ -0,0 +1,288 @@
+//! Warping functions from uniform samples in [0, 1)^2 to common domains.
+//! Every function returns the sampled value together with its pdf, with
+//! directions measured in solid angle and points in area. Not every warp
+//! has a caller yet; those are kept for the lights and shapes that need them.
+
+use crate::vec3::Vec3;
+
+use std::f32::consts::{FRAC_1_PI, FRAC_PI_2, FRAC_PI_4, PI};
+
+#[derive(Copy, Clone)]
+pub struct DirectionSample {
+    pub direction: Vec3,
+    pub pdf: f32,
+}
+
+#[derive(Copy, Clone)]
+pub struct PointSample {
+    pub point: Vec3,
+    pub pdf: f32,
+}
+
+/// Orthonormal basis around a unit normal, used to move samples generated
+/// around +z into world space.
+#[derive(Copy, Clone)]
+pub struct Frame {
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    pub normal: Vec3,
+}
+
+impl Frame {
+    /// Builds a frame from a unit `normal` with the branchless construction
+    /// of Duff et al., "Building an Orthonormal Basis, Revisited" (2017).
+    pub fn from_normal(normal: Vec3) -> Frame {
+        let sign = 1.0f32.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+        Frame {
+            tangent: Vec3::new(
+                1.0 + sign * normal.x * normal.x * a,
+                sign * b,
+                -sign * normal.x,
+            ),
+            bitangent: Vec3::new(b, sign + normal.y * normal.y * a, -normal.y),
+            normal,
+        }
+    }
+
+    pub fn local_to_world(&self, v: Vec3) -> Vec3 {
+        self.tangent
+            .scale(v.x)
+            .add(self.bitangent.scale(v.y))
+            .add(self.normal.scale(v.z))
+    }
+}
+
+pub fn uniform_sphere(u: (f32, f32)) -> DirectionSample {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    DirectionSample {
+        direction: Vec3::new(r * phi.cos(), r * phi.sin(), z),
+        pdf: uniform_sphere_pdf(),
+    }
+}
+
+pub fn uniform_sphere_pdf() -> f32 {
+    0.25 * FRAC_1_PI
+}
+
+#[allow(dead_code)]
+pub fn uniform_hemisphere(normal: Vec3, u: (f32, f32)) -> DirectionSample {
+    let z = u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    DirectionSample {
+        direction: Frame::from_normal(normal).local_to_world(Vec3::new(
+            r * phi.cos(),
+            r * phi.sin(),
+            z,
+        )),
+        pdf: uniform_hemisphere_pdf(),
+    }
+}
+
+#[allow(dead_code)]
+pub fn uniform_hemisphere_pdf() -> f32 {
+    0.5 * FRAC_1_PI
+}
+
+/// Shirley and Chiu's concentric mapping onto the unit disk, which keeps
+/// stratification intact. Returns the point in the z = 0 plane.
+pub fn concentric_disk(u: (f32, f32)) -> PointSample {
+    let ox = 2.0 * u.0 - 1.0;
+    let oy = 2.0 * u.1 - 1.0;
+    let point = if ox == 0.0 && oy == 0.0 {
+        Vec3::zero()
+    } else {
+        let (r, theta) = if ox.abs() > oy.abs() {
+            (ox, FRAC_PI_4 * (oy / ox))
+        } else {
+            (oy, FRAC_PI_2 - FRAC_PI_4 * (ox / oy))
+        };
+        Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+    };
+    PointSample {
+        point,
+        pdf: FRAC_1_PI,
+    }
+}
+
+/// Cosine weighted hemisphere around `normal` using Malley's method.
+pub fn cosine_hemisphere(normal: Vec3, u: (f32, f32)) -> DirectionSample {
+    let disk = concentric_disk(u);
+    let d = disk.point;
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+    // Projecting up to the hemisphere scales the density by cos theta.
+    DirectionSample {
+        direction: Frame::from_normal(normal).local_to_world(Vec3::new(d.x, d.y, z)),
+        pdf: disk.pdf * z,
+    }
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f32) -> f32 {
+    cos_theta.max(0.0) * FRAC_1_PI
+}
+
+/// Uniform direction inside the cone around `axis` with half angle
+/// `acos(cos_theta_max)`, such as the solid angle subtended by a sphere.
+#[allow(dead_code)]
+pub fn uniform_cone(axis: Vec3, cos_theta_max: f32, u: (f32, f32)) -> DirectionSample {
+    let cos_theta = 1.0 - u.0 * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    DirectionSample {
+        direction: Frame::from_normal(axis).local_to_world(Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        )),
+        pdf: uniform_cone_pdf(cos_theta_max),
+    }
+}
+
+#[allow(dead_code)]
+pub fn uniform_cone_pdf(cos_theta_max: f32) -> f32 {
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+}
+
+/// Uniform point on the triangle (`p0`, `p1`, `p2`), using the
+/// warping of Heitz, "A Low-Distortion Map Between Triangle and Square"
+/// (2019).
+#[allow(dead_code)]
+pub fn uniform_triangle(p0: Vec3, p1: Vec3, p2: Vec3, u: (f32, f32)) -> PointSample {
+    let (b0, b1) = if u.0 < u.1 {
+        let b0 = u.0 / 2.0;
+        (b0, u.1 - b0)
+    } else {
+        let b1 = u.1 / 2.0;
+        (u.0 - b1, b1)
+    };
+    let b2 = 1.0 - b0 - b1;
+    let area = p1.sub(p0).cross(p2.sub(p0)).magnitude() * 0.5;
+    PointSample {
+        point: p0.scale(b0).add(p1.scale(b1)).add(p2.scale(b2)),
+        pdf: 1.0 / area,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{Pcg32, Rng};
+
+    const SAMPLES: usize = 100_000;
+
+    /// Checks that the average of `1 / pdf` matches `measure`, the size of
+    /// the sampled domain, which only holds if the pdf is normalized.
+    fn assert_measure<F>(measure: f32, mut sample: F)
+    where
+        F: FnMut((f32, f32)) -> f32,
+    {
+        let mut rng = Pcg32::new(11, 0);
+        let mut sum = 0.0f64;
+        for _ in 0..SAMPLES {
+            let pdf = sample((rng.uni(), rng.uni()));
+            assert!(pdf > 0.0);
+            sum += 1.0 / pdf as f64;
+        }
+        let estimate = (sum / SAMPLES as f64) as f32;
+        assert!(
+            (estimate - measure).abs() < measure * 0.01,
+            "estimated {}, expected {}",
+            estimate,
+            measure
+        );
+    }
+
+    #[test]
+    fn pdfs_are_normalized() {
+        let normal = Vec3::new(0.3, -0.5, 0.8).normalize();
+        let assert_unit = |d: Vec3| assert!((d.magnitude() - 1.0).abs() < 1e-4);
+
+        assert_measure(4.0 * PI, |u| {
+            let s = uniform_sphere(u);
+            assert_unit(s.direction);
+            s.pdf
+        });
+        assert_measure(2.0 * PI, |u| {
+            let s = uniform_hemisphere(normal, u);
+            assert_unit(s.direction);
+            assert!(s.direction.dot(normal) >= -1e-6);
+            s.pdf
+        });
+        assert_measure(PI, |u| concentric_disk(u).pdf);
+        assert_measure(2.0 * PI * 0.25, |u| {
+            let s = uniform_cone(normal, 0.75, u);
+            assert_unit(s.direction);
+            assert!(s.direction.dot(normal) >= 0.75 - 1e-4);
+            s.pdf
+        });
+
+        // Cosine weighting: E[cos / pdf] over the hemisphere is pi.
+        let mut rng = Pcg32::new(5, 0);
+        let mut sum = 0.0f64;
+        for _ in 0..SAMPLES {
+            let s = cosine_hemisphere(normal, (rng.uni(), rng.uni()));
+            assert_unit(s.direction);
+            if s.pdf > 0.0 {
+                sum += (s.direction.dot(normal) / s.pdf) as f64;
+            }
+        }
+        assert!(((sum / SAMPLES as f64) as f32 - PI).abs() < 1e-3);
+
+        let (p0, p1, p2) = triangle();
+        let area = p1.sub(p0).cross(p2.sub(p0)).magnitude() * 0.5;
+        assert_measure(area, |u| uniform_triangle(p0, p1, p2, u).pdf);
+    }
+
+    fn triangle() -> (Vec3, Vec3, Vec3) {
+        (
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 3.0, 1.0),
+        )
+    }
+
+    /// Bins the barycentrics of the samples on an 8 by 8 grid. Cells fully
+    /// inside the triangle cover 2 / 64 of it and those cut by the diagonal
+    /// half as much.
+    #[test]
+    fn triangle_samples_are_inside_and_uniform() {
+        const CELLS: usize = 8;
+        let (p0, p1, p2) = triangle();
+        let twice_area = p1.sub(p0).cross(p2.sub(p0)).magnitude();
+        let mut counts = [0usize; CELLS * CELLS];
+        let mut rng = Pcg32::new(3, 0);
+        for _ in 0..SAMPLES {
+            let p = uniform_triangle(p0, p1, p2, (rng.uni(), rng.uni())).point;
+            let b0 = p1.sub(p).cross(p2.sub(p)).magnitude() / twice_area;
+            let b1 = p2.sub(p).cross(p0.sub(p)).magnitude() / twice_area;
+            let b2 = p0.sub(p).cross(p1.sub(p)).magnitude() / twice_area;
+            // The unsigned areas only add up to the whole inside.
+            assert!((b0 + b1 + b2 - 1.0).abs() < 1e-4, "sample outside");
+            let cell = |b: f32| ((b * CELLS as f32) as usize).min(CELLS - 1);
+            counts[cell(b1) * CELLS + cell(b0)] += 1;
+        }
+        for i in 0..CELLS {
+            for j in 0..CELLS {
+                let count = counts[i * CELLS + j] as f32;
+                let fraction = match i + j {
+                    k if k < CELLS - 1 => 2.0,
+                    k if k == CELLS - 1 => 1.0,
+                    _ => 0.0,
+                } / (CELLS * CELLS) as f32;
+                let expected = fraction * SAMPLES as f32;
+                assert!(
+                    (count - expected).abs() <= 5.0 * expected.sqrt() + 1.0,
+                    "cell ({}, {}) has {} samples, expected {}",
+                    j,
+                    i,
+                    count,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use crate::material::{Material, Model, Subsurface};
+use crate::plane::Plane;
+use crate::principled::Principled;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sphere::Sphere;
+use crate::stats;
+use crate::vec3::Vec3;
+use crate::volume::Volume;
+
+pub const MIN_DISTANCE: f32 = 0.0001;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SceneKind {
+    CornellBox,
+    /// The Cornell box with the material sample spheres.
+    Materials,
+}
+
+impl SceneKind {
+    pub fn from_name(name: &str) -> Option<SceneKind> {
+        Some(match name {
+            "cornell" => SceneKind::CornellBox,
+            "materials" => SceneKind::Materials,
+            _ => return None,
+        })
+    }
+
+    pub fn create(&self) -> Scene {
+        match self {
+            SceneKind::CornellBox => Scene::cornell_box(),
+            SceneKind::Materials => Scene::material_samples(),
+        }
+    }
+}
+
+pub struct Scene {
+    pub materials: Vec<Material>,
+    pub spheres: Vec<(Sphere, u8)>,
+    pub planes: Vec<(Plane, u8)>,
+    pub volumes: Vec<Volume>,
+}
+
+#[derive(Copy, Clone)]
+pub struct Hit {
+    pub t: f32,
+    pub normal: Vec3,
+    pub material: u8,
+    /// Index of the object that was hit, counting spheres, then planes.
+    pub object: usize,
+}
+
+impl Scene {
+    /// The default box scene: a closed room with colored side walls, a
+    /// diffuse and a mirror sphere, and an emissive sphere as the ceiling
+    /// light.
+    pub fn cornell_box() -> Scene {
+        let materials = vec![
+            Material {
+                reflection: Vec3::new(1.0, 1.0, 1.0),
+                emission: Vec3::new(0.0, 0.0, 0.0),
+                scattering: 1.0,
+                model: Model::Standard,
+            },
+            Material {
+                reflection: Vec3::new(1.0, 1.0, 1.0),
+                emission: Vec3::new(0.0, 0.0, 0.0),
+                scattering: 0.0,
+                model: Model::Standard,
+            },
+            Material {
+                reflection: Vec3::new(0.3, 0.3, 1.0),
+                emission: Vec3::new(0.0, 0.0, 0.0),
+                scattering: 1.0,
+                model: Model::Standard,
+            },
+            Material {
+                reflection: Vec3::new(1.0, 0.3, 0.3),
+                emission: Vec3::new(0.0, 0.0, 0.0),
+                scattering: 1.0,
+                model: Model::Standard,
+            },
+            Material {
+                reflection: Vec3::new(1.0, 1.0, 1.0),
+                emission: Vec3::new(0.7, 0.7, 0.7),
+                scattering: 1.0,
+                model: Model::Standard,
+            },
+        ];
+
+        let spheres = vec![
+            (
+                Sphere {
+                    center: Vec3::new(0.25, -0.3, -0.15),
+                    radius: 0.2,
+                },
+                0,
+            ),
+            (
+                Sphere {
+                    center: Vec3::new(-0.25, -0.3, -0.25),
+                    radius: 0.2,
+                },
+                1,
+            ),
+            (
+                Sphere {
+                    center: Vec3::new(0.0, 0.6, -0.25),
+                    radius: 0.25,
+                },
+                4,
+            ),
+        ];
+
+        let planes = vec![
+            (
+                Plane {
+                    normal: Vec3::new(0.0, 1.0, 0.0),
+                    distance: -0.5,
+                },
+                0,
+            ),
+            (
+                Plane {
+                    normal: Vec3::new(0.0, -1.0, 0.0),
+                    distance: -0.5,
+                },
+                0,
+            ),
+            (
+                Plane {
+                    normal: Vec3::new(0.0, 0.0, -1.0),
+                    distance: -0.5,
+                },
+                0,
+            ),
+            (
+                Plane {
+                    normal: Vec3::new(0.0, 0.0, 1.0),
+                    distance: -0.5,
+                },
+                0,
+            ),
+            (
+                Plane {
+                    normal: Vec3::new(-1.0, 0.0, 0.0),
+                    distance: -0.5,
+                },
+                2,
+            ),
+            (
+                Plane {
+                    normal: Vec3::new(1.0, 0.0, 0.0),
+                    distance: -0.5,
+                },
+                3,
+            ),
+        ];
+
+        Scene {
+            materials,
+            spheres,
+            planes,
+            volumes: Vec::new(),
+        }
+    }
+
+    /// The box scene with small spheres in front showing off the material
+    /// models.
+    pub fn material_samples() -> Scene {
+        let mut scene = Scene::cornell_box();
+        let samples = [
+            (
+                Vec3::new(0.0, -0.42, 0.05),
+                Model::Subsurface(Subsurface {
+                    albedo: Vec3::new(0.99, 0.95, 0.85),
+                    mean_free_path: Vec3::new(0.05, 0.025, 0.01),
+                }),
+            ),
+            (
+                Vec3::new(0.25, -0.42, 0.1),
+                Model::Principled(Principled {
+                    base_color: Vec3::new(0.95, 1.0, 0.97),
+                    roughness: 0.0,
+                    transmission: 1.0,
+                    ior: 1.5,
+                    abbe: 30.0,
+                    ..Principled::default()
+                }),
+            ),
+            (
+                Vec3::new(-0.25, -0.42, 0.1),
+                Model::Principled(Principled {
+                    base_color: Vec3::new(0.8, 0.1, 0.05),
+                    roughness: 0.6,
+                    sheen: 0.5,
+                    clearcoat: 1.0,
+                    ..Principled::default()
+                }),
+            ),
+        ];
+        for (center, model) in samples {
+            let material = scene.materials.len() as u8;
+            scene.materials.push(Material {
+                reflection: Vec3::one(),
+                emission: Vec3::zero(),
+                scattering: 1.0,
+                model,
+            });
+            let sphere = Sphere {
+                center,
+                radius: 0.08,
+            };
+            scene.spheres.push((sphere, material));
+        }
+        scene
+    }
+
+    pub fn intersect(&self, ray: Ray) -> Option<Hit> {
+        stats::count(|s| {
+            s.rays += 1;
+            s.sphere_tests += self.spheres.len() as u64;
+            s.plane_tests += self.planes.len() as u64;
+        });
+
+        let mut hit: Option<Hit> = None;
+        let mut min = f32::MAX;
+
+        for (object, (s, i)) in self.spheres.iter().enumerate() {
+            let t = s.intersect(ray);
+            if t > MIN_DISTANCE && t < min {
+                min = t;
+                hit = Some(Hit {
+                    t,
+                    normal: s.normal(ray.at(t)),
+                    material: *i,
+                    object,
+                });
+            }
+        }
+
+        for (index, (p, i)) in self.planes.iter().enumerate() {
+            let t = p.intersect(ray);
+            if t > MIN_DISTANCE && t < min {
+                min = t;
+                hit = Some(Hit {
+                    t,
+                    normal: p.normal,
+                    material: *i,
+                    object: self.spheres.len() + index,
+                });
+            }
+        }
+
+        hit
+    }
+
+    /// Returns the nearest real collision with any volume before `t_max`,
+    /// together with the index of the volume.
+    pub fn sample_volumes(
+        &self,
+        ray: Ray,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(f32, usize)> {
+        let mut collision = None;
+        let mut min = t_max;
+
+        for (i, v) in self.volumes.iter().enumerate() {
+            if let Some(t) = v.sample_collision(ray, min, sampler) {
+                min = t;
+                collision = Some((t, i));
+            }
+        }
+
+        collision
+    }
+
+    /// Fraction of light passing through every volume along `ray` up to
+    /// `t_max`, for rays that only test visibility. Each volume contributes
+    /// a ratio tracking estimate, so the result is noisy but unbiased.
+    pub fn transmittance(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> f32 {
+        self.volumes
+            .iter()
+            .map(|v| v.transmittance(ray, t_max, sampler))
+            .product()
+    }
+
+    /// Surface coordinates of `point` on the sphere or plane `object`.
+    pub fn uv(&self, object: usize, point: Vec3) -> (f32, f32) {
+        match self.spheres.get(object) {
+            Some((sphere, _)) => sphere.uv(point),
+            None => self.planes[object - self.spheres.len()].0.uv(point),
+        }
+    }
+
+    /// Object index of the volume at `index`, numbered after all surfaces.
+    pub fn volume_object(&self, index: usize) -> usize {
+        self.spheres.len() + self.planes.len() + index
+    }
+
+    /// Emissive spheres that light paths can start on, each picked with a
+    /// probability proportional to its power.
+    pub fn lights(&self) -> Vec<Light> {
+        let powers: Vec<(usize, f32)> = self
+            .spheres
+            .iter()
+            .enumerate()
+            .map(|(i, (sphere, material))| {
+                let emission = self.materials[*material as usize].emission;
+                (i, emission.luminance() * sphere.radius * sphere.radius)
+            })
+            .filter(|(_, power)| *power > 0.0)
+            .collect();
+        let total: f32 = powers.iter().map(|(_, p)| p).sum();
+        powers
+            .into_iter()
+            .map(|(sphere, power)| Light {
+                sphere,
+                pdf: power / total,
+            })
+            .collect()
+    }
+}
+
+pub struct Light {
+    /// Index of the emissive sphere, which is also its object index.
+    pub sphere: usize,
+    /// Probability of picking this light.
+    pub pdf: f32,
+}
+
+/// Picks one of `lights` by their probabilities with the uniform value `u`.
+pub fn pick_light(lights: &[Light], u: f32) -> Option<&Light> {
+    let mut cdf = 0.0;
+    lights
+        .iter()
+        .find(|l| {
+            cdf += l.pdf;
+            u < cdf
+        })
+        .or(lights.last())
+}
@@ -0,0 +1,223 @@
+//! Work stealing scheduler for the render tiles.
+//!
+//! The tiles are dealt out in the requested order as one contiguous run per
+//! thread. Every thread works through its own run from the front and, once
+//! it is empty, steals from the back of the longest run left. Threads thus
+//! finish together no matter how uneven the tiles are, while the front of
+//! the order is still rendered first.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TileOrder {
+    /// Row by row from the top of the image.
+    Scanline,
+    /// Outwards from the center tile, so the subject shows up first.
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles close together.
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        Some(match name {
+            "scanline" => TileOrder::Scanline,
+            "spiral" => TileOrder::Spiral,
+            "hilbert" => TileOrder::Hilbert,
+            _ => return None,
+        })
+    }
+
+    /// Every tile of a `tiles_x` by `tiles_y` grid exactly once, in order.
+    pub fn tiles(&self, tiles_x: usize, tiles_y: usize) -> Vec<(usize, usize)> {
+        match self {
+            TileOrder::Scanline => (0..tiles_y)
+                .flat_map(|y| (0..tiles_x).map(move |x| (x, y)))
+                .collect(),
+            TileOrder::Spiral => spiral(tiles_x, tiles_y),
+            TileOrder::Hilbert => {
+                let n = tiles_x.max(tiles_y).next_power_of_two();
+                (0..n * n)
+                    .map(|d| hilbert(n, d))
+                    .filter(|&(x, y)| x < tiles_x && y < tiles_y)
+                    .collect()
+            }
+        }
+    }
+}
+
+fn spiral(tiles_x: usize, tiles_y: usize) -> Vec<(usize, usize)> {
+    let total = tiles_x * tiles_y;
+    let mut tiles = Vec::with_capacity(total);
+    let (mut x, mut y) = ((tiles_x as isize - 1) / 2, (tiles_y as isize - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let visit = |x: isize, y: isize, tiles: &mut Vec<(usize, usize)>| {
+        if x >= 0 && y >= 0 && (x as usize) < tiles_x && (y as usize) < tiles_y {
+            tiles.push((x as usize, y as usize));
+        }
+    };
+    visit(x, y, &mut tiles);
+    // Legs of a square spiral grow by one every second turn.
+    let mut leg = 1;
+    let mut turn = 0;
+    while tiles.len() < total {
+        let (dx, dy) = directions[turn % 4];
+        for _ in 0..leg {
+            x += dx;
+            y += dy;
+            visit(x, y, &mut tiles);
+        }
+        turn += 1;
+        if turn % 2 == 0 {
+            leg += 1;
+        }
+    }
+    tiles
+}
+
+/// Position of the `d`th cell along the Hilbert curve filling an `n` by `n`
+/// grid, with `n` a power of two.
+fn hilbert(n: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/// When and where a task ran, relative to the start of `run`.
+#[derive(Copy, Clone)]
+pub struct TaskTiming {
+    pub thread: usize,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Calls `work` once for every task on `threads` threads, starting them in
+/// the order of the indices in `order`, and returns the timing of each task
+/// by its index in `tasks`. Tasks missing from `order` are not run.
+pub fn run<T, F>(tasks: &mut [T], order: &[usize], threads: usize, work: F) -> Vec<TaskTiming>
+where
+    T: Send,
+    F: Fn(&mut T) + Sync,
+{
+    let threads = threads.clamp(1, order.len().max(1));
+    let mut slots: Vec<Option<&mut T>> = tasks.iter_mut().map(Some).collect();
+    let queues: Vec<Mutex<VecDeque<(usize, &mut T)>>> = (0..threads)
+        .map(|t| {
+            let run = &order[t * order.len() / threads..(t + 1) * order.len() / threads];
+            let queue = run
+                .iter()
+                .map(|&i| (i, slots[i].take().expect("task scheduled twice")))
+                .collect();
+            Mutex::new(queue)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let timings = Mutex::new(vec![None; slots.len()]);
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            let (queues, timings, work) = (&queues, &timings, &work);
+            scope.spawn(move || {
+                while let Some((i, task)) = next_task(queues, thread) {
+                    let started = start.elapsed();
+                    work(task);
+                    timings.lock().unwrap()[i] = Some(TaskTiming {
+                        thread,
+                        start: started,
+                        duration: start.elapsed() - started,
+                    });
+                }
+            });
+        }
+    });
+
+    let zero = TaskTiming {
+        thread: 0,
+        start: Duration::ZERO,
+        duration: Duration::ZERO,
+    };
+    let timings = timings.into_inner().unwrap();
+    timings.into_iter().map(|t| t.unwrap_or(zero)).collect()
+}
+
+/// The front of the thread's own queue, or else the back of the longest
+/// other one.
+fn next_task<'a, T>(
+    queues: &[Mutex<VecDeque<(usize, &'a mut T)>>],
+    thread: usize,
+) -> Option<(usize, &'a mut T)> {
+    if let Some(task) = queues[thread].lock().unwrap().pop_front() {
+        return Some(task);
+    }
+    loop {
+        let (victim, len) = queues
+            .iter()
+            .map(|q| q.lock().unwrap().len())
+            .enumerate()
+            .max_by_key(|&(_, len)| len)?;
+        if len == 0 {
+            return None;
+        }
+        // The victim may have been emptied since it was picked.
+        if let Some(task) = queues[victim].lock().unwrap().pop_back() {
+            return Some(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_visit_every_tile_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            for (w, h) in [(1, 1), (5, 3), (2, 7), (8, 8)] {
+                let mut tiles = order.tiles(w, h);
+                assert_eq!(tiles.len(), w * h, "{:?} {}x{}", order, w, h);
+                tiles.sort();
+                tiles.dedup();
+                assert_eq!(tiles.len(), w * h, "{:?} {}x{}", order, w, h);
+            }
+        }
+        assert_eq!(TileOrder::Spiral.tiles(3, 3)[0], (1, 1));
+        let hilbert = TileOrder::Hilbert.tiles(8, 8);
+        for pair in hilbert.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert_eq!(a.0.abs_diff(b.0) + a.1.abs_diff(b.1), 1);
+        }
+    }
+
+    #[test]
+    fn runs_every_scheduled_task_once() {
+        let mut tasks: Vec<(u64, u32)> = (0..100).map(|i| (i, 0)).collect();
+        let order: Vec<usize> = (0..100).rev().filter(|i| i % 10 != 0).collect();
+        let timings = run(&mut tasks, &order, 4, |(cost, runs)| {
+            // Uneven tasks make the threads steal from each other.
+            std::thread::sleep(Duration::from_micros(*cost * 10));
+            *runs += 1;
+        });
+        for (i, (_, runs)) in tasks.iter().enumerate() {
+            assert_eq!(*runs, if i % 10 == 0 { 0 } else { 1 });
+        }
+        assert!(timings.iter().all(|t| t.thread < 4));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::math::clamp;
+use crate::vec3::Vec3;
+
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 720.0;
+
+/// Number of wavelengths carried by each path: one hero wavelength plus
+/// equally spaced rotations of it across the visible range.
+pub const WAVELENGTHS: usize = 4;
+
+#[derive(Copy, Clone)]
+pub struct SampledSpectrum(pub [f32; WAVELENGTHS]);
+
+impl SampledSpectrum {
+    pub const fn splat(v: f32) -> SampledSpectrum {
+        SampledSpectrum([v; WAVELENGTHS])
+    }
+
+    pub fn add(&self, rhs: SampledSpectrum) -> SampledSpectrum {
+        let mut out = *self;
+        for (o, r) in out.0.iter_mut().zip(rhs.0.iter()) {
+            *o += r;
+        }
+        out
+    }
+
+    pub fn mul(&self, rhs: SampledSpectrum) -> SampledSpectrum {
+        let mut out = *self;
+        for (o, r) in out.0.iter_mut().zip(rhs.0.iter()) {
+            *o *= r;
+        }
+        out
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct SampledWavelengths {
+    pub lambda: [f32; WAVELENGTHS],
+    pdf: [f32; WAVELENGTHS],
+}
+
+impl SampledWavelengths {
+    /// Hero wavelength sampling: `u` picks the hero uniformly over the
+    /// visible range and the remaining wavelengths are stratified from it.
+    pub fn sample(u: f32) -> SampledWavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let delta = range / WAVELENGTHS as f32;
+        let mut lambda = [0.0; WAVELENGTHS];
+        for (i, l) in lambda.iter_mut().enumerate() {
+            let mut v = LAMBDA_MIN + u * range + delta * i as f32;
+            if v > LAMBDA_MAX {
+                v -= range;
+            }
+            *l = v;
+        }
+        SampledWavelengths {
+            lambda,
+            pdf: [1.0 / range; WAVELENGTHS],
+        }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|p| *p == 0.0)
+    }
+
+    /// Drops all but the hero wavelength, for events such as dispersion
+    /// where the path can no longer be shared between wavelengths.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for p in self.pdf[1..].iter_mut() {
+            *p = 0.0;
+        }
+        self.pdf[0] /= WAVELENGTHS as f32;
+    }
+
+    /// Upsamples a linear sRGB reflectance or radiance to these wavelengths.
+    pub fn upsample(&self, rgb: Vec3) -> SampledSpectrum {
+        let mut out = [0.0; WAVELENGTHS];
+        for (o, l) in out.iter_mut().zip(self.lambda.iter()) {
+            *o = rgb_to_spectrum(rgb, *l);
+        }
+        SampledSpectrum(out)
+    }
+
+    /// Converts a radiance estimate to CIE XYZ, then to linear sRGB.
+    pub fn radiance_to_rgb(&self, s: SampledSpectrum) -> Vec3 {
+        let mut xyz = Vec3::zero();
+        for i in 0..WAVELENGTHS {
+            if self.pdf[i] == 0.0 {
+                continue;
+            }
+            let l = self.lambda[i];
+            let w = s.0[i] / self.pdf[i];
+            xyz = xyz.add(Vec3::new(cie_x(l), cie_y(l), cie_z(l)).scale(w));
+        }
+        let xyz = xyz.scale(1.0 / (CIE_Y_INTEGRAL * WAVELENGTHS as f32));
+        let rgb = xyz_to_srgb(xyz);
+        Vec3::new(
+            rgb.x / WHITE_RGB.x,
+            rgb.y / WHITE_RGB.y,
+            rgb.z / WHITE_RGB.z,
+        )
+    }
+}
+
+fn gaussian(l: f32, mu: f32, sigma_low: f32, sigma_high: f32) -> f32 {
+    let sigma = if l < mu { sigma_low } else { sigma_high };
+    let t = (l - mu) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+// Multi-lobe analytic fit of the CIE 1931 color matching functions from
+// Wyman, Sloan and Shirley, "Simple Analytic Approximations to the CIE XYZ
+// Color Matching Functions" (2013).
+fn cie_x(l: f32) -> f32 {
+    1.056 * gaussian(l, 599.8, 37.9, 31.0) + 0.362 * gaussian(l, 442.0, 16.0, 26.7)
+        - 0.065 * gaussian(l, 501.1, 20.4, 26.2)
+}
+
+fn cie_y(l: f32) -> f32 {
+    0.821 * gaussian(l, 568.8, 46.9, 40.5) + 0.286 * gaussian(l, 530.9, 16.3, 31.1)
+}
+
+fn cie_z(l: f32) -> f32 {
+    1.217 * gaussian(l, 437.0, 11.8, 36.0) + 0.681 * gaussian(l, 459.0, 26.0, 13.8)
+}
+
+/// Integral of `cie_y` over [LAMBDA_MIN, LAMBDA_MAX].
+const CIE_Y_INTEGRAL: f32 = 106.911_87;
+
+/// Linear sRGB of a constant unit spectrum, used to white balance the
+/// equal-energy white point to sRGB white.
+const WHITE_RGB: Vec3 = Vec3::new(1.200_606, 0.949_638, 0.907_908);
+
+fn xyz_to_srgb(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        3.240_454 * xyz.x - 1.537_139 * xyz.y - 0.498_531 * xyz.z,
+        -0.969_266 * xyz.x + 1.876_011 * xyz.y + 0.041_556 * xyz.z,
+        0.055_643 * xyz.x - 0.204_026 * xyz.y + 1.057_225 * xyz.z,
+    )
+}
+
+// Basis spectra from Smits, "An RGB-to-Spectrum Conversion for
+// Reflectances" (1999), sampled in 10 bins over [LAMBDA_MIN, LAMBDA_MAX].
+const SMITS_WHITE: [f32; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f32; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f32; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f32; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f32; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f32; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f32; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+fn basis(table: &[f32; 10], l: f32) -> f32 {
+    let bin = (l - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.0 - 0.5;
+    let bin = clamp(bin, 0.0, 9.0);
+    let i = (bin as usize).min(8);
+    let t = bin - i as f32;
+    table[i] * (1.0 - t) + table[i + 1] * t
+}
+
+fn rgb_to_spectrum(rgb: Vec3, l: f32) -> f32 {
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    if r <= g && r <= b {
+        let mut v = r * basis(&SMITS_WHITE, l);
+        if g <= b {
+            v += (g - r) * basis(&SMITS_CYAN, l) + (b - g) * basis(&SMITS_BLUE, l);
+        } else {
+            v += (b - r) * basis(&SMITS_CYAN, l) + (g - b) * basis(&SMITS_GREEN, l);
+        }
+        v
+    } else if g <= r && g <= b {
+        let mut v = g * basis(&SMITS_WHITE, l);
+        if r <= b {
+            v += (r - g) * basis(&SMITS_MAGENTA, l) + (b - r) * basis(&SMITS_BLUE, l);
+        } else {
+            v += (b - g) * basis(&SMITS_MAGENTA, l) + (r - b) * basis(&SMITS_RED, l);
+        }
+        v
+    } else {
+        let mut v = b * basis(&SMITS_WHITE, l);
+        if r <= g {
+            v += (r - b) * basis(&SMITS_YELLOW, l) + (g - r) * basis(&SMITS_GREEN, l);
+        } else {
+            v += (g - b) * basis(&SMITS_YELLOW, l) + (r - g) * basis(&SMITS_RED, l);
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Averages the RGB estimate of a white emitter seen through `bounces`
+    /// white reflections over stratified hero wavelengths.
+    fn white_estimate(bounces: usize, dispersive: bool) -> Vec3 {
+        const SAMPLES: usize = 10_000;
+        let mut sum = Vec3::zero();
+        for i in 0..SAMPLES {
+            let mut lambda = SampledWavelengths::sample((i as f32 + 0.5) / SAMPLES as f32);
+            let mut radiance = lambda.upsample(Vec3::one());
+            for _ in 0..bounces {
+                radiance = radiance.mul(lambda.upsample(Vec3::one()));
+            }
+            if dispersive {
+                lambda.terminate_secondary();
+            }
+            sum = sum.add(lambda.radiance_to_rgb(radiance));
+        }
+        sum.scale(1.0 / SAMPLES as f32)
+    }
+
+    #[test]
+    fn constant_white_round_trips_to_white() {
+        let lambda = SampledWavelengths::sample(0.3);
+        for v in lambda.upsample(Vec3::one()).0.iter() {
+            assert!((v - 1.0).abs() < 1e-3, "white upsampled to {}", v);
+        }
+        for &(bounces, dispersive) in &[(0, false), (3, false), (1, true)] {
+            let rgb = white_estimate(bounces, dispersive);
+            for c in [rgb.x, rgb.y, rgb.z] {
+                assert!(
+                    (c - 1.0).abs() < 0.01,
+                    "white after {} bounces became ({}, {}, {})",
+                    bounces,
+                    rgb.x,
+                    rgb.y,
+                    rgb.z
+                );
+            }
+        }
+    }
+}
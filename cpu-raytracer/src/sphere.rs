@@ -1,6 +1,10 @@
+use crate::math::clamp;
 use crate::ray::Ray;
+use crate::scene::MIN_DISTANCE;
 use crate::vec3::Vec3;
 
+use std::f32::consts::FRAC_1_PI;
+
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
@@ -17,15 +21,28 @@ impl Sphere {
         if discriminant < 0.0 {
             -1.0
         } else {
-            // let term = discriminant.sqrt() / a;
-            // let t0 = -half_b + term;
-            // let t1 = -half_b - term;
-            // Some(if t1 > t0 { t0 } else { t1 })
-            (-half_b - discriminant.sqrt()) / a
+            // Fall back to the far root so rays starting inside the sphere,
+            // such as subsurface walks, still find the exit point.
+            let root = discriminant.sqrt();
+            let near = (-half_b - root) / a;
+            if near > MIN_DISTANCE {
+                near
+            } else {
+                (-half_b + root) / a
+            }
         }
     }
 
     pub fn normal(&self, point: Vec3) -> Vec3 {
         point.sub(self.center).normalize()
     }
+
+    /// Spherical coordinates of `point` in [0, 1]: `u` around the y axis
+    /// and `v` from the bottom pole to the top.
+    pub fn uv(&self, point: Vec3) -> (f32, f32) {
+        let n = self.normal(point);
+        let u = n.z.atan2(n.x) * (0.5 * FRAC_1_PI) + 0.5;
+        let v = 1.0 - clamp(n.y, -1.0, 1.0).acos() * FRAC_1_PI;
+        (u, v)
+    }
 }
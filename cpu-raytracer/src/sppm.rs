@@ -0,0 +1,477 @@
+//! Stochastic progressive photon mapping after Hachisuka and Jensen,
+//! "Stochastic Progressive Photon Mapping" (2009), with the update rules of
+//! pbrt's `SPPMIntegrator`.
+//!
+//! Every iteration traces one camera path per pixel through specular and
+//! other non-diffuse surfaces up to the first diffuse surface, its visible
+//! point, then traces photons from the lights into a kd-tree. The photons
+//! around each visible point are added to the pixel's running estimate and
+//! its gather radius shrinks, so the estimate converges even for caustics
+//! such as those of the mirror sphere that the path tracer cannot find.
+//! Volumes only absorb photons, by a ratio tracking estimate of their
+//! transmittance; they neither scatter light nor affect camera paths.
+//!
+//! The sky emits no photons. Its light is only estimated directly at the
+//! visible points with one shadow ray each, so sky light that reaches them
+//! through other diffuse surfaces is missing.
+
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::integrator::{sky_color, BOUNCES};
+use crate::kdtree::KdTree;
+use crate::progress::Reporter;
+use crate::ray::Ray;
+use crate::render::Settings;
+use crate::sampler::{hash, Sampler};
+use crate::sampling::{cosine_hemisphere, uniform_sphere};
+use crate::scene::{pick_light, Light, Scene};
+use crate::stats;
+use crate::vec3::Vec3;
+
+use std::f32::consts::{FRAC_1_PI, PI};
+
+/// Fraction of the newly found photons kept each iteration; controls how
+/// fast the radius shrinks.
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// Photons only count at visible points whose normal is within about 25
+/// degrees of their own, so light does not leak around corners.
+const MIN_NORMAL_COSINE: f32 = 0.9;
+
+/// Photons traced by a single task of the photon pass.
+const PHOTON_BATCH: usize = 4096;
+
+#[derive(Copy, Clone)]
+struct VisiblePoint {
+    point: Vec3,
+    /// Geometric normal facing the camera side.
+    normal: Vec3,
+    /// Throughput from the camera times the lambertian BSDF.
+    beta: Vec3,
+}
+
+#[derive(Copy, Clone)]
+struct PixelState {
+    /// Light the camera paths met before their visible points, summed over
+    /// all iterations.
+    emitted: Vec3,
+    visible: Option<VisiblePoint>,
+    radius: f32,
+    /// Flux gathered within `radius` and the number of photons it is
+    /// attributed to.
+    tau: Vec3,
+    photons: f32,
+}
+
+struct Photon {
+    /// Geometric normal facing the side the photon arrived from.
+    normal: Vec3,
+    power: Vec3,
+}
+
+/// Renders `scene` with `settings.samples` iterations of one camera path
+/// per pixel and `settings.sppm_photons` photons each. The estimates are
+/// written to the film's splats, one sample per pixel, and do not depend on
+/// the thread count.
+pub fn render(scene: &Scene, camera: &Camera, settings: &Settings) -> Film {
+    let (width, height) = (settings.width, settings.height);
+    let lights = scene.lights();
+    let iterations = settings.samples;
+    let mut pixels = vec![
+        PixelState {
+            emitted: Vec3::zero(),
+            visible: None,
+            radius: settings.sppm_radius,
+            tau: Vec3::zero(),
+            photons: 0.0,
+        };
+        width * height
+    ];
+    let mut pool = scoped_threadpool::Pool::new(settings.threads);
+    let progress = Reporter::new(settings);
+    progress.add_work((iterations * (width * height + settings.sppm_photons)) as u64);
+
+    for iteration in 0..iterations {
+        pool.scoped(|scope| {
+            for (y, row) in pixels.chunks_mut(width).enumerate() {
+                let progress = &progress;
+                scope.execute(move || {
+                    let start = stats::snapshot();
+                    let mut sampler =
+                        settings
+                            .sampler
+                            .create(iterations, settings.seed, settings.rng);
+                    let sampler = sampler.as_mut();
+                    for (x, p) in row.iter_mut().enumerate() {
+                        sampler.start_pixel_sample(x, y, iteration);
+                        let (jitter_x, jitter_y) = sampler.get_2d();
+                        let u = (x as f32 + jitter_x) / width as f32;
+                        let v = (y as f32 + jitter_y) / height as f32;
+                        let ray = camera.ray_from_uv(u, v);
+                        stats::count(|s| s.primary_rays += 1);
+                        let (emitted, visible) = visible_point(scene, ray, sampler);
+                        p.emitted = p.emitted.add(emitted);
+                        p.visible = visible;
+                    }
+                    progress.advance(width as u64, &stats::snapshot().since(&start));
+                });
+            }
+        });
+
+        let tree = trace_photons(scene, &lights, settings, iteration, &progress, &mut pool);
+
+        pool.scoped(|scope| {
+            for row in pixels.chunks_mut(width) {
+                let tree = &tree;
+                scope.execute(move || {
+                    for p in row.iter_mut() {
+                        gather(p, tree);
+                    }
+                });
+            }
+        });
+    }
+
+    let mut film = Film::new(width, height, settings.filter, Vec::new());
+    film.stats = progress.finish();
+    film.count_samples((width * height) as u64);
+    let photons = (iterations * settings.sppm_photons) as f32;
+    for (i, p) in pixels.iter().enumerate() {
+        let area = PI * p.radius * p.radius;
+        let color = p
+            .emitted
+            .scale(1.0 / iterations as f32)
+            .add(p.tau.scale(1.0 / (photons * area)));
+        let (x, y) = (i % width, i / width);
+        film.splats().add(x as f32 + 0.5, y as f32 + 0.5, color);
+    }
+    film
+}
+
+/// Follows a camera path to its first diffuse surface. Returns the light
+/// emitted towards the camera along the way and the visible point, if the
+/// path got there.
+fn visible_point(
+    scene: &Scene,
+    mut ray: Ray,
+    sampler: &mut dyn Sampler,
+) -> (Vec3, Option<VisiblePoint>) {
+    let mut beta = Vec3::one();
+    let mut emitted = Vec3::zero();
+    for _ in 0..BOUNCES {
+        let hit = match scene.intersect(ray) {
+            Some(hit) => hit,
+            None => return (emitted.add(beta.hadamard(sky_color(ray))), None),
+        };
+        let material = scene.materials[hit.material as usize];
+        emitted = emitted.add(beta.hadamard(material.emission));
+
+        if let Some(albedo) = material.lambertian() {
+            let normal = if hit.normal.dot(ray.direction) > 0.0 {
+                hit.normal.scale(-1.0)
+            } else {
+                hit.normal
+            };
+            let visible = VisiblePoint {
+                point: ray.at(hit.t),
+                normal,
+                beta: beta.hadamard(albedo).scale(FRAC_1_PI),
+            };
+            let sky = direct_sky(scene, &visible, sampler);
+            return (emitted.add(sky), Some(visible));
+        }
+
+        match material.scatter(ray, &hit, scene, None, sampler) {
+            Some(scatter) => {
+                beta = beta.hadamard(scatter.attenuation);
+                ray = scatter.ray;
+            }
+            None => break,
+        }
+    }
+    (emitted, None)
+}
+
+/// Estimates the sky light arriving directly at `visible` with one cosine
+/// weighted shadow ray.
+fn direct_sky(scene: &Scene, visible: &VisiblePoint, sampler: &mut dyn Sampler) -> Vec3 {
+    let ray = Ray {
+        origin: visible.point,
+        direction: cosine_hemisphere(visible.normal, sampler.get_2d()).direction,
+    };
+    stats::count(|s| s.shadow_rays += 1);
+    if scene.intersect(ray).is_some() {
+        return Vec3::zero();
+    }
+    // The pdf of cos / pi cancels the cosine and the 1 / pi of the BSDF.
+    let transmittance = scene.transmittance(ray, f32::MAX, sampler);
+    visible
+        .beta
+        .hadamard(sky_color(ray))
+        .scale(PI * transmittance)
+}
+
+/// Traces the photons of one iteration and stores every diffuse hit.
+fn trace_photons(
+    scene: &Scene,
+    lights: &[Light],
+    settings: &Settings,
+    iteration: usize,
+    progress: &Reporter,
+    pool: &mut scoped_threadpool::Pool,
+) -> KdTree<Photon> {
+    let count = settings.sppm_photons;
+    let mut batches: Vec<Vec<(Vec3, Photon)>> = (0..count.div_ceil(PHOTON_BATCH))
+        .map(|_| Vec::new())
+        .collect();
+
+    pool.scoped(|scope| {
+        for (b, batch) in batches.iter_mut().enumerate() {
+            scope.execute(move || {
+                // Photons use their own seed so they are not correlated with
+                // the camera paths of the pixels sharing their index.
+                let seed = hash(&[settings.seed, 1]);
+                let mut sampler = settings
+                    .sampler
+                    .create(settings.samples, seed, settings.rng);
+                let sampler = sampler.as_mut();
+                let start = stats::snapshot();
+                let photons = b * PHOTON_BATCH..count.min((b + 1) * PHOTON_BATCH);
+                let traced = photons.len() as u64;
+                for i in photons {
+                    sampler.start_pixel_sample(i, 0, iteration);
+                    trace_photon(scene, lights, sampler, batch);
+                }
+                progress.advance(traced, &stats::snapshot().since(&start));
+            });
+        }
+    });
+
+    KdTree::build(batches.into_iter().flatten().collect())
+}
+
+fn trace_photon(
+    scene: &Scene,
+    lights: &[Light],
+    sampler: &mut dyn Sampler,
+    photons: &mut Vec<(Vec3, Photon)>,
+) {
+    let light = match pick_light(lights, sampler.get_1d()) {
+        Some(light) => light,
+        None => return,
+    };
+    let (sphere, material) = &scene.spheres[light.sphere];
+    let normal = uniform_sphere(sampler.get_2d()).direction;
+    let pdf_pos = light.pdf / (4.0 * PI * sphere.radius * sphere.radius);
+    let mut ray = Ray {
+        origin: sphere.center.add(normal.scale(sphere.radius)),
+        direction: cosine_hemisphere(normal, sampler.get_2d()).direction,
+    };
+    // Le * cos / (pdf_pos * pdf_dir), with the cosine cancelling.
+    let emission = scene.materials[*material as usize].emission;
+    let mut beta = emission.scale(PI / pdf_pos);
+
+    for _ in 0..BOUNCES {
+        let hit = match scene.intersect(ray) {
+            Some(hit) => hit,
+            None => return,
+        };
+        // Volumes only absorb photons; they are not scattered inside them.
+        beta = beta.scale(scene.transmittance(ray, hit.t, sampler));
+        let material = scene.materials[hit.material as usize];
+        if material.lambertian().is_some() {
+            let normal = if hit.normal.dot(ray.direction) > 0.0 {
+                hit.normal.scale(-1.0)
+            } else {
+                hit.normal
+            };
+            let photon = Photon {
+                normal,
+                power: beta,
+            };
+            photons.push((ray.at(hit.t), photon));
+        }
+
+        match material.scatter(ray, &hit, scene, None, sampler) {
+            Some(scatter) => {
+                beta = beta.hadamard(scatter.attenuation);
+                ray = scatter.ray;
+            }
+            None => return,
+        }
+    }
+}
+
+/// Adds the photons around the pixel's visible point to its estimate and
+/// shrinks its radius.
+fn gather(p: &mut PixelState, tree: &KdTree<Photon>) {
+    let visible = match p.visible {
+        Some(visible) => visible,
+        None => return,
+    };
+
+    let mut flux = Vec3::zero();
+    let mut found = 0;
+    tree.for_each_within(visible.point, p.radius, |_, photon| {
+        if photon.normal.dot(visible.normal) >= MIN_NORMAL_COSINE {
+            flux = flux.add(photon.power);
+            found += 1;
+        }
+    });
+    if found == 0 {
+        return;
+    }
+
+    let photons = p.photons + ALPHA * found as f32;
+    let radius = p.radius * (photons / (p.photons + found as f32)).sqrt();
+    let shrink = (radius / p.radius) * (radius / p.radius);
+    p.tau = p.tau.add(visible.beta.hadamard(flux)).scale(shrink);
+    p.photons = photons;
+    p.radius = radius;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator;
+    use crate::material::{Material, Model};
+    use crate::plane::Plane;
+    use crate::random::RngKind;
+    use crate::sampler::SamplerKind;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn cornell_box(name: &str, samples: usize) -> Film {
+        let settings = Settings {
+            width: 16,
+            height: 12,
+            samples,
+            sppm_photons: 5000,
+            threads: 1,
+            integrator: integrator::find(name).unwrap(),
+            ..Settings::default()
+        };
+        let camera = Camera::look_at(
+            Vec3::new(0.0, 0.0, 0.5),
+            Vec3::new(0.0, -0.25, -1.0),
+            FRAC_PI_2,
+            settings.width as f32 / settings.height as f32,
+        );
+        crate::render::render(&Scene::cornell_box(), &camera, &settings)
+    }
+
+    fn mean_luminance(film: &Film) -> f32 {
+        let sum = film.resolve().iter().map(|c| c.luminance()).sum::<f32>();
+        sum / (film.width * film.height) as f32
+    }
+
+    #[test]
+    fn radius_shrinks_as_photons_are_gathered() {
+        // One photon of unit power every 0.01 on the plane y = 0.
+        let photons = (-100..=100)
+            .flat_map(|x| (-100..=100).map(move |z| (x, z)))
+            .map(|(x, z)| {
+                let point = Vec3::new(x as f32 * 0.01, 0.0, z as f32 * 0.01);
+                let photon = Photon {
+                    normal: Vec3::new(0.0, 1.0, 0.0),
+                    power: Vec3::one(),
+                };
+                (point, photon)
+            })
+            .collect();
+        let tree = KdTree::build(photons);
+        let mut p = PixelState {
+            emitted: Vec3::zero(),
+            visible: Some(VisiblePoint {
+                point: Vec3::zero(),
+                normal: Vec3::new(0.0, 1.0, 0.0),
+                beta: Vec3::one(),
+            }),
+            radius: 0.2,
+            tau: Vec3::zero(),
+            photons: 0.0,
+        };
+
+        for iteration in 1..=16 {
+            let radius = p.radius;
+            gather(&mut p, &tree);
+            assert!(p.radius < radius, "radius grew to {}", p.radius);
+            // The estimate stays at the photon density, 10^4 per unit area.
+            let density = p.tau.x / (iteration as f32 * PI * p.radius * p.radius);
+            assert!(
+                (density / 1e4 - 1.0).abs() < 0.1,
+                "density {} after {} iterations",
+                density,
+                iteration
+            );
+        }
+        assert!(p.radius < 0.12, "radius only shrank to {}", p.radius);
+    }
+
+    #[test]
+    fn converges_to_the_path_tracer() {
+        let path = cornell_box("path", 64);
+        let variance = (0..path.height)
+            .flat_map(|y| (0..path.width).map(move |x| (x, y)))
+            .map(|(x, y)| path.variance(x, y))
+            .sum::<f32>();
+        let sigma = variance.sqrt() / (path.width * path.height) as f32;
+
+        let (expected, sppm) = (
+            mean_luminance(&path),
+            mean_luminance(&cornell_box("sppm", 16)),
+        );
+        assert!(
+            (expected - sppm).abs() < 5.0 * sigma,
+            "path tracer {} and photon mapping {} differ by more than 5 sigma ({})",
+            expected,
+            sppm,
+            sigma
+        );
+    }
+
+    /// A visible point on an open floor sees the whole upper hemisphere of
+    /// the sky, whose cosine weighted mean is its colour at y = 2 / 3.
+    #[test]
+    fn direct_sky_lights_an_open_floor() {
+        let grey = Material {
+            reflection: Vec3::new(0.5, 0.5, 0.5),
+            emission: Vec3::zero(),
+            scattering: 1.0,
+            model: Model::Standard,
+        };
+        let floor = Plane {
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            distance: 0.0,
+        };
+        let scene = Scene {
+            materials: vec![grey],
+            spheres: Vec::new(),
+            planes: vec![(floor, 0)],
+            volumes: Vec::new(),
+        };
+        let visible = VisiblePoint {
+            point: Vec3::new(0.0, 0.0001, 0.0),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            beta: Vec3::one().scale(0.5 * FRAC_1_PI),
+        };
+
+        let mut sampler = SamplerKind::Independent.create(1, 3, RngKind::Pcg32);
+        let n = 4096;
+        let mut sum = Vec3::zero();
+        for i in 0..n {
+            sampler.start_pixel_sample(i, 0, 0);
+            sum = sum.add(direct_sky(&scene, &visible, sampler.as_mut()));
+        }
+        let mean = sum.scale(1.0 / n as f32);
+        let expected = Vec3::one()
+            .lerp(Vec3::new(0.5, 0.7, 1.0), 5.0 / 6.0)
+            .scale(0.5);
+        for (m, e) in [
+            (mean.x, expected.x),
+            (mean.y, expected.y),
+            (mean.z, expected.z),
+        ] {
+            assert!((m - e).abs() < 0.01, "sky estimate {} instead of {}", m, e);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Counters of the work done while rendering. Every thread counts into its
+//! own `Stats`, which only ever grow; workers take the difference of two
+//! snapshots around each task and the render sums those up on the film.
+
+use std::cell::Cell;
+
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub struct Stats {
+    /// Rays started at the camera.
+    pub primary_rays: u64,
+    /// Rays only testing the visibility between two points.
+    pub shadow_rays: u64,
+    /// Every ray traced through the scene, including the above.
+    pub rays: u64,
+    /// Intersection tests by primitive. Every ray is tested against every
+    /// sphere and plane, as there is no acceleration structure.
+    pub sphere_tests: u64,
+    pub plane_tests: u64,
+    pub volume_bounds_tests: u64,
+    /// Camera samples whose radiance was NaN or infinite. They are dropped.
+    pub nonfinite_samples: u64,
+    /// Camera samples scaled down to the maximum luminance.
+    pub clamped_samples: u64,
+}
+
+thread_local! {
+    static STATS: Cell<Stats> = const {
+        Cell::new(Stats {
+            primary_rays: 0,
+            shadow_rays: 0,
+            rays: 0,
+            sphere_tests: 0,
+            plane_tests: 0,
+            volume_bounds_tests: 0,
+            nonfinite_samples: 0,
+            clamped_samples: 0,
+        })
+    };
+}
+
+/// Adds to the calling thread's counters.
+pub fn count(f: impl FnOnce(&mut Stats)) {
+    STATS.with(|s| {
+        let mut stats = s.get();
+        f(&mut stats);
+        s.set(stats);
+    });
+}
+
+/// Everything the calling thread counted so far.
+pub fn snapshot() -> Stats {
+    STATS.with(|s| s.get())
+}
+
+impl Stats {
+    /// Counts since the `earlier` snapshot of the same thread.
+    pub fn since(&self, earlier: &Stats) -> Stats {
+        Stats {
+            primary_rays: self.primary_rays - earlier.primary_rays,
+            shadow_rays: self.shadow_rays - earlier.shadow_rays,
+            rays: self.rays - earlier.rays,
+            sphere_tests: self.sphere_tests - earlier.sphere_tests,
+            plane_tests: self.plane_tests - earlier.plane_tests,
+            volume_bounds_tests: self.volume_bounds_tests - earlier.volume_bounds_tests,
+            nonfinite_samples: self.nonfinite_samples - earlier.nonfinite_samples,
+            clamped_samples: self.clamped_samples - earlier.clamped_samples,
+        }
+    }
+
+    pub fn add(&mut self, other: &Stats) {
+        self.primary_rays += other.primary_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.rays += other.rays;
+        self.sphere_tests += other.sphere_tests;
+        self.plane_tests += other.plane_tests;
+        self.volume_bounds_tests += other.volume_bounds_tests;
+        self.nonfinite_samples += other.nonfinite_samples;
+        self.clamped_samples += other.clamped_samples;
+    }
+
+    pub fn intersection_tests(&self) -> u64 {
+        self.sphere_tests + self.plane_tests + self.volume_bounds_tests
+    }
+
+    /// Rays other than shadow rays per primary ray: the segments of a
+    /// camera path, plus those of the light paths traced for it by
+    /// bidirectional integrators.
+    pub fn mean_path_length(&self) -> f64 {
+        if self.primary_rays == 0 {
+            return 0.0;
+        }
+        (self.rays - self.shadow_rays) as f64 / self.primary_rays as f64
+    }
+
+    pub fn summary(&self) -> String {
+        [
+            format!("Primary rays:           {}", self.primary_rays),
+            format!("Shadow rays:            {}", self.shadow_rays),
+            format!("Rays total:             {}", self.rays),
+            format!("Mean path length:       {:.3}", self.mean_path_length()),
+            format!("Sphere tests:           {}", self.sphere_tests),
+            format!("Plane tests:            {}", self.plane_tests),
+            format!("Volume bounds tests:    {}", self.volume_bounds_tests),
+            format!("Non-finite samples:     {}", self.nonfinite_samples),
+            format!("Clamped samples:        {}", self.clamped_samples),
+        ]
+        .join("\n")
+    }
+
+    pub fn json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"primary_rays\":{},\"shadow_rays\":{},\"rays\":{},",
+                "\"mean_path_length\":{:.6},\"intersection_tests\":{{\"sphere\":{},",
+                "\"plane\":{},\"volume_bounds\":{}}},\"nonfinite_samples\":{},",
+                "\"clamped_samples\":{}}}"
+            ),
+            self.primary_rays,
+            self.shadow_rays,
+            self.rays,
+            self.mean_path_length(),
+            self.sphere_tests,
+            self.plane_tests,
+            self.volume_bounds_tests,
+            self.nonfinite_samples,
+            self.clamped_samples
+        )
+    }
+}
@@ -91,6 +91,22 @@ impl Vec3 {
         }
     }
 
+    pub fn min(&self, rhs: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x.min(rhs.x),
+            y: self.y.min(rhs.y),
+            z: self.z.min(rhs.z),
+        }
+    }
+
+    pub fn max(&self, rhs: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x.max(rhs.x),
+            y: self.y.max(rhs.y),
+            z: self.z.max(rhs.z),
+        }
+    }
+
     pub fn normalize(&self) -> Vec3 {
         let inv_mag = 1.0 / self.magnitude();
         Vec3 {
@@ -112,6 +128,15 @@ impl Vec3 {
         }
     }
 
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    /// Relative luminance of a linear sRGB color.
+    pub fn luminance(&self) -> f32 {
+        self.x * 0.2126 + self.y * 0.7152 + self.z * 0.0722
+    }
+
     pub fn lerp(&self, rhs: Vec3, t: f32) -> Vec3 {
         self.scale(1.0 - t).add(rhs.scale(t))
     }
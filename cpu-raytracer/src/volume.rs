@@ -0,0 +1,321 @@
+use crate::aabb::Aabb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::stats;
+use crate::vec3::Vec3;
+
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Dense voxel grid loaded from a raw little-endian binary file.
+///
+/// The file starts with four `u32` values: the grid resolution along x, y
+/// and z followed by the channel count (1 for density only, 4 for density
+/// plus RGB emission). The voxel data follows as `f32` values, interleaved
+/// per voxel with x varying fastest, then y, then z.
+pub struct VoxelGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    density: Vec<f32>,
+    emission: Option<Vec<Vec3>>,
+    max_density: f32,
+}
+
+impl VoxelGrid {
+    pub fn load(path: &str) -> io::Result<VoxelGrid> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<VoxelGrid> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let word = |i: usize| -> Option<[u8; 4]> {
+            let b = bytes.get(i * 4..i * 4 + 4)?;
+            Some([b[0], b[1], b[2], b[3]])
+        };
+
+        let header = |i: usize| word(i).map(|w| u32::from_le_bytes(w) as usize);
+        let (nx, ny, nz, channels) = match (header(0), header(1), header(2), header(3)) {
+            (Some(nx), Some(ny), Some(nz), Some(c)) => (nx, ny, nz, c),
+            _ => return Err(invalid("voxel grid header is truncated")),
+        };
+
+        if nx == 0 || ny == 0 || nz == 0 {
+            return Err(invalid("voxel grid has a zero dimension"));
+        }
+        if channels != 1 && channels != 4 {
+            return Err(invalid("voxel grid must have 1 or 4 channels"));
+        }
+
+        let size_mismatch = || invalid("voxel grid size does not match its header");
+        let voxels = nx
+            .checked_mul(ny)
+            .and_then(|v| v.checked_mul(nz))
+            .ok_or_else(size_mismatch)?;
+        let size = voxels
+            .checked_mul(channels)
+            .and_then(|v| v.checked_add(4))
+            .and_then(|v| v.checked_mul(4))
+            .ok_or_else(size_mismatch)?;
+        if bytes.len() != size {
+            return Err(size_mismatch());
+        }
+
+        let value = |i: usize| f32::from_le_bytes(word(4 + i).unwrap());
+
+        let density: Vec<f32> = (0..voxels).map(|v| value(v * channels).max(0.0)).collect();
+        let emission = if channels == 4 {
+            Some(
+                (0..voxels)
+                    .map(|v| {
+                        let i = v * channels;
+                        Vec3::new(value(i + 1), value(i + 2), value(i + 3))
+                    })
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let max_density = density.iter().cloned().fold(0.0, f32::max);
+
+        Ok(VoxelGrid {
+            nx,
+            ny,
+            nz,
+            density,
+            emission,
+            max_density,
+        })
+    }
+
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        (z * self.ny + y) * self.nx + x
+    }
+
+    /// Voxel indices and trilinear weights around `uvw`, given in [0, 1]
+    /// grid space.
+    fn corners(&self, uvw: Vec3) -> [(usize, f32); 8] {
+        let axis = |t: f32, n: usize| {
+            let p = (t * n as f32 - 0.5).max(0.0).min(n as f32 - 1.0);
+            let i0 = (p as usize).min(n - 1);
+            let i1 = (i0 + 1).min(n - 1);
+            [(i0, 1.0 - (p - i0 as f32)), (i1, p - i0 as f32)]
+        };
+
+        let xs = axis(uvw.x, self.nx);
+        let ys = axis(uvw.y, self.ny);
+        let zs = axis(uvw.z, self.nz);
+
+        let mut corners = [(0, 0.0); 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let (x, wx) = xs[i & 1];
+            let (y, wy) = ys[(i >> 1) & 1];
+            let (z, wz) = zs[i >> 2];
+            *corner = (self.index(x, y, z), wx * wy * wz);
+        }
+        corners
+    }
+
+    pub fn density(&self, uvw: Vec3) -> f32 {
+        self.corners(uvw)
+            .iter()
+            .map(|&(i, w)| self.density[i] * w)
+            .sum()
+    }
+
+    pub fn emission(&self, uvw: Vec3) -> Vec3 {
+        match &self.emission {
+            Some(emission) => self
+                .corners(uvw)
+                .iter()
+                .fold(Vec3::zero(), |sum, &(i, w)| sum.add(emission[i].scale(w))),
+            None => Vec3::zero(),
+        }
+    }
+}
+
+/// Heterogeneous participating medium filling `bounds` with a voxel grid.
+pub struct Volume {
+    pub grid: VoxelGrid,
+    pub bounds: Aabb,
+    pub density_scale: f32,
+    pub albedo: Vec3,
+    pub emission_scale: f32,
+}
+
+impl Volume {
+    fn grid_coordinates(&self, point: Vec3) -> Vec3 {
+        let size = self.bounds.size();
+        let local = point.sub(self.bounds.min);
+        Vec3::new(local.x / size.x, local.y / size.y, local.z / size.z)
+    }
+
+    fn majorant(&self) -> f32 {
+        self.grid.max_density * self.density_scale
+    }
+
+    pub fn density(&self, point: Vec3) -> f32 {
+        self.grid.density(self.grid_coordinates(point)) * self.density_scale
+    }
+
+    pub fn emission(&self, point: Vec3) -> Vec3 {
+        self.grid
+            .emission(self.grid_coordinates(point))
+            .scale(self.emission_scale)
+    }
+
+    /// Delta tracking: returns the distance along `ray` of the first real
+    /// collision before `t_max`, or `None` if the ray passes through.
+    pub fn sample_collision(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> Option<f32> {
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return None;
+        }
+
+        stats::count(|s| s.volume_bounds_tests += 1);
+        let (t_enter, t_exit) = self.bounds.intersect(ray)?;
+        let t_exit = t_exit.min(t_max);
+        // Ray directions are not normalized, so convert the sampled world
+        // space free-flight distances into ray parameter space.
+        let step = 1.0 / (majorant * ray.direction.magnitude());
+
+        let mut t = t_enter;
+        loop {
+            t -= (1.0 - sampler.get_1d()).ln() * step;
+            if t >= t_exit {
+                return None;
+            }
+            if sampler.get_1d() * majorant < self.density(ray.at(t)) {
+                return Some(t);
+            }
+        }
+    }
+
+    /// Ratio tracking estimate of the transmittance along `ray` up to `t_max`.
+    pub fn transmittance(&self, ray: Ray, t_max: f32, sampler: &mut dyn Sampler) -> f32 {
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+
+        stats::count(|s| s.volume_bounds_tests += 1);
+        let (t_enter, t_exit) = match self.bounds.intersect(ray) {
+            Some(range) => range,
+            None => return 1.0,
+        };
+        let t_exit = t_exit.min(t_max);
+        let step = 1.0 / (majorant * ray.direction.magnitude());
+
+        let mut transmittance = 1.0;
+        let mut t = t_enter;
+        loop {
+            t -= (1.0 - sampler.get_1d()).ln() * step;
+            if t >= t_exit {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density(ray.at(t)) / majorant;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::RngKind;
+    use crate::sampler::SamplerKind;
+
+    fn grid_bytes(header: [u32; 4], values: &[f32]) -> Vec<u8> {
+        header
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .chain(values.iter().flat_map(|v| v.to_le_bytes()))
+            .collect()
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match VoxelGrid::from_bytes(bytes) {
+            Ok(_) => panic!("invalid grid accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert_eq!(error(&[0; 12]), "voxel grid header is truncated");
+        assert_eq!(
+            error(&grid_bytes([0, 1, 1, 1], &[])),
+            "voxel grid has a zero dimension"
+        );
+        assert_eq!(
+            error(&grid_bytes([1, 1, 1, 2], &[0.0; 2])),
+            "voxel grid must have 1 or 4 channels"
+        );
+        assert_eq!(
+            error(&grid_bytes([2, 2, 2, 1], &[0.0; 7])),
+            "voxel grid size does not match its header"
+        );
+        let max = u32::MAX;
+        assert_eq!(
+            error(&grid_bytes([max, max, max, 4], &[0.0; 4])),
+            "voxel grid size does not match its header"
+        );
+        assert!(VoxelGrid::from_bytes(&grid_bytes([2, 2, 2, 1], &[0.5; 8])).is_ok());
+    }
+
+    /// Both estimators must match Beer-Lambert on a constant grid.
+    #[test]
+    fn tracking_matches_beer_lambert() {
+        const SAMPLES: usize = 20_000;
+        let sigma = 1.5;
+        let grid = VoxelGrid::from_bytes(&grid_bytes([2, 2, 2, 1], &[1.0; 8])).unwrap();
+        let volume = Volume {
+            grid,
+            bounds: Aabb {
+                min: Vec3::new(-1.0, -1.0, -1.0),
+                max: Vec3::new(1.0, 1.0, 1.0),
+            },
+            density_scale: sigma,
+            albedo: Vec3::one(),
+            emission_scale: 0.0,
+        };
+        // Unnormalized direction, so distances are not ray parameters.
+        let ray = Ray {
+            origin: Vec3::new(-3.0, 0.2, 0.0),
+            direction: Vec3::new(2.0, 0.0, 0.0),
+        };
+        let expected = (-sigma * 2.0f32).exp();
+
+        let mut sampler = SamplerKind::Independent.create(1, 9, RngKind::Pcg32);
+        sampler.start_pixel_sample(0, 0, 0);
+        let mut passed = 0;
+        let mut transmittance = 0.0;
+        for _ in 0..SAMPLES {
+            if volume
+                .sample_collision(ray, f32::MAX, &mut *sampler)
+                .is_none()
+            {
+                passed += 1;
+            }
+            transmittance += volume.transmittance(ray, f32::MAX, &mut *sampler);
+        }
+
+        let sigma_mean = (expected * (1.0 - expected) / SAMPLES as f32).sqrt();
+        let passed = passed as f32 / SAMPLES as f32;
+        assert!(
+            (passed - expected).abs() < 5.0 * sigma_mean,
+            "delta tracking passed {}, expected {}",
+            passed,
+            expected
+        );
+        let transmittance = transmittance / SAMPLES as f32;
+        assert!(
+            (transmittance - expected).abs() < 5.0 * sigma_mean,
+            "ratio tracking estimated {}, expected {}",
+            transmittance,
+            expected
+        );
+    }
+}
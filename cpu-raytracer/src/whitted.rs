@@ -0,0 +1,105 @@
+//! Whitted style preview: direct lighting with hard shadows and perfect
+//! mirror reflections only. It is deterministic apart from the pixel
+//! jitter, so a single sample per pixel gives a noise-free image for
+//! framing shots. Only the shadows of volumes are noisy, as their
+//! transmittance is estimated by ratio tracking.
+//!
+//! Emissive spheres are lit from as point lights at their centers with the
+//! intensity of a disk of the same radiance and radius. Every material but
+//! the perfect mirror is shaded as a diffuse surface with its albedo.
+
+use crate::film::Aovs;
+use crate::integrator::{record_first_hit, sky_color, Integrator, SampleOutput, BOUNCES};
+use crate::material::Model;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::stats;
+use crate::vec3::Vec3;
+
+use std::f32::consts::PI;
+
+pub struct Whitted;
+
+impl Integrator for Whitted {
+    fn li(
+        &self,
+        ray: Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        output: &mut SampleOutput,
+    ) -> Vec3 {
+        whitted(ray, scene, sampler, &mut output.aovs)
+    }
+}
+
+/// Radiance arriving along the camera `ray`.
+fn whitted(ray: Ray, scene: &Scene, sampler: &mut dyn Sampler, aovs: &mut Aovs) -> Vec3 {
+    let mut ray = ray;
+    let mut beta = Vec3::one();
+    let mut color = Vec3::zero();
+    for bounce in 0..BOUNCES {
+        let hit = match scene.intersect(ray) {
+            Some(hit) => hit,
+            None => return color.add(beta.hadamard(sky_color(ray))),
+        };
+        let material = scene.materials[hit.material as usize];
+        if bounce == 0 {
+            let albedo = material.albedo();
+            let id = Some(hit.material);
+            record_first_hit(aovs, ray, hit.t, albedo, hit.normal, id, hit.object);
+        }
+
+        color = color.add(beta.hadamard(material.emission));
+        let point = ray.at(hit.t);
+        let normal = if hit.normal.dot(ray.direction) > 0.0 {
+            hit.normal.scale(-1.0)
+        } else {
+            hit.normal
+        };
+
+        if matches!(material.model, Model::Standard) && material.scattering == 0.0 {
+            let direction = ray
+                .direction
+                .sub(normal.scale(normal.dot(ray.direction) * 2.0));
+            beta = beta.hadamard(material.reflection.scale(0.5));
+            ray = Ray {
+                origin: point,
+                direction,
+            };
+            continue;
+        }
+
+        // Lambertian BSDF with the albedo the path tracer's diffuse bounce
+        // attenuates by.
+        let f = material.albedo().scale(0.5 / PI);
+        for (object, (sphere, light)) in scene.spheres.iter().enumerate() {
+            let emission = scene.materials[*light as usize].emission;
+            if object == hit.object || emission.square_magnitude() == 0.0 {
+                continue;
+            }
+            let to_light = sphere.center.sub(point);
+            let distance_squared = to_light.square_magnitude();
+            let cos = normal.dot(to_light.normalize());
+            if cos <= 0.0 {
+                continue;
+            }
+            let shadow = Ray {
+                origin: point,
+                direction: to_light,
+            };
+            stats::count(|s| s.shadow_rays += 1);
+            let t_light = match scene.intersect(shadow) {
+                Some(h) if h.object != object => continue,
+                Some(h) => h.t,
+                None => 1.0,
+            };
+            let transmittance = scene.transmittance(shadow, t_light, sampler);
+            let intensity = emission.scale(PI * sphere.radius * sphere.radius);
+            let irradiance = intensity.scale(transmittance * cos / distance_squared);
+            color = color.add(beta.hadamard(f).hadamard(irradiance));
+        }
+        break;
+    }
+    color
+}